@@ -1,9 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, stderr, stdout};
-use std::net::{SocketAddr, UdpSocket};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{BufReader, Read, Write, stderr, stdout};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
@@ -14,23 +17,1061 @@ const WRQ: u16 = 2; // Write request
 const DATA: u16 = 3; // Data packet
 const ACK: u16 = 4;  // Acknowledgment packet
 const ERROR: u16 = 5; // Error packet
+const OACK: u16 = 6; // Option acknowledgment (RFC 2347)
 
 // Error codes
+const ERROR_NOT_DEFINED: u16 = 0;
 const ERROR_FILE_NOT_FOUND: u16 = 1;
 const ERROR_ACCESS_VIOLATION: u16 = 2;
+const ERROR_DISK_FULL: u16 = 3;
 const ERROR_ILLEGAL_OPERATION: u16 = 4;
+const ERROR_UNKNOWN_TID: u16 = 5;
+const ERROR_FILE_EXISTS: u16 = 6;
+const ERROR_NO_SUCH_USER: u16 = 7;
+const ERROR_OPTION_NEGOTIATION: u16 = 8;
 
 // WiFi-optimized constants
 const MAX_RETRIES: usize = 8; // Increased for WiFi
 const INITIAL_TIMEOUT_MS: u64 = 1000; // Start with 1 second
 const MAX_TIMEOUT_MS: u64 = 5000; // Max 5 seconds
+const MIN_UTIMEOUT_MS: u64 = 10; // Bounds for the utimeout option
+const MAX_UTIMEOUT_MS: u64 = 255_000;
 const PACKET_SIZE: usize = 512; // Standard TFTP packet size
+const MIN_BLKSIZE: usize = 8; // RFC 2348 limits
+const MAX_BLKSIZE: usize = 65464;
+const MAX_WINDOWSIZE: usize = 64; // Blocks in flight per window (RFC 7440)
 
 #[derive(Debug)]
 struct TFTPServer {
+    /// Set for virtual servers started with --server
+    name: Option<String>,
     port: u16,
     directory: PathBuf,
-    active_transfers: Arc<Mutex<HashMap<String, bool>>>,
+    ip_mode: IpMode,
+    /// Addresses to listen on; empty means every address on `port`
+    listen: Vec<SocketAddr>,
+    /// Network device all sockets are bound to (SO_BINDTODEVICE)
+    interface: Option<String>,
+    /// First and last local port for transfer sockets, instead of any
+    /// ephemeral port
+    port_range: Option<(u16, u16)>,
+    /// Where the next search for a free port in `port_range` starts
+    next_port: Arc<AtomicU32>,
+    /// Run every session over the listening socket instead of a new TID
+    single_port: bool,
+    /// In single-port mode, the listening socket this copy of the server
+    /// answers on
+    listener: Option<Arc<UdpSocket>>,
+    /// In single-port mode, where the listener loop forwards packets from
+    /// each client with a live session
+    routes: Routes,
+    access: Access,
+    /// Client access rules, checked in order; `None` lets everyone in
+    acl: Option<Arc<Vec<AclRule>>>,
+    /// Filename rewriting rules, applied before anything else looks at a name
+    map: Option<Arc<Vec<MapRule>>>,
+    absolute_paths: AbsolutePaths,
+    /// Other roots for some clients, tried in order before `directory`
+    client_roots: Arc<Vec<ClientRoot>>,
+    /// Refuse uploads that would replace an existing file
+    no_overwrite: bool,
+    max_upload_size: Option<u64>,
+    /// Most bytes the served directory may hold for uploads to be accepted
+    quota: Option<u64>,
+    /// Most bytes each client address may upload while the server runs
+    client_quota: Option<u64>,
+    /// Bytes uploaded so far by each client address, running uploads included
+    client_uploads: Arc<Mutex<HashMap<IpAddr, u64>>>,
+    /// What each served directory holds, for `quota`
+    directory_usage: Arc<Mutex<HashMap<PathBuf, DirectoryUsage>>>,
+    rollover: Rollover,
+    /// Largest blksize and windowsize granted to clients
+    max_blksize: usize,
+    max_windowsize: usize,
+    /// First group address and port handed out to multicast sessions
+    multicast_base: Option<SocketAddrV4>,
+    multicast_sessions: Arc<Mutex<HashMap<MulticastKey, MulticastSession>>>,
+    /// Live sessions and the flag that cancels each one
+    active_transfers: Arc<Mutex<HashMap<TransferKey, Arc<AtomicBool>>>>,
+}
+
+/// Packet channels of single-port sessions, by client address
+type Routes = Arc<Mutex<HashMap<SocketAddr, Arc<Sender<Vec<u8>>>>>>;
+
+/// How a session exchanges packets with its client
+#[derive(Debug)]
+enum Transport {
+    /// A socket of its own, whose port is the server's TID (RFC 1350)
+    Socket(UdpSocket),
+    /// The listening socket, shared with every other session. The listener
+    /// loop routes this client's packets to `inbox`.
+    Shared {
+        socket: Arc<UdpSocket>,
+        inbox: Receiver<Vec<u8>>,
+        peer: SocketAddr,
+        route: Arc<Sender<Vec<u8>>>,
+        routes: Routes,
+    },
+}
+
+impl Transport {
+    fn send_to(&self, packet: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        match self {
+            Transport::Socket(socket) => socket.send_to(packet, addr),
+            Transport::Shared { socket, .. } => send_from_listener(socket, packet, addr),
+        }
+    }
+
+    /// Waits up to `timeout` for the next packet
+    fn recv_from(&self, buffer: &mut [u8], timeout: Duration) -> std::io::Result<(usize, SocketAddr)> {
+        match self {
+            Transport::Socket(socket) => {
+                socket.set_read_timeout(Some(timeout))?;
+                socket.recv_from(buffer)
+            }
+            Transport::Shared { inbox, peer, .. } => match inbox.recv_timeout(timeout) {
+                Ok(packet) => {
+                    let size = std::cmp::min(packet.len(), buffer.len());
+                    buffer[..size].copy_from_slice(&packet[..size]);
+                    Ok((size, *peer))
+                }
+                Err(RecvTimeoutError::Timeout) => Err(std::io::ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => Err(std::io::ErrorKind::BrokenPipe.into()),
+            },
+        }
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        // A newer session for the same client may have taken the route over
+        if let Transport::Shared { peer, route, routes, .. } = self {
+            let mut routes = routes.lock().unwrap();
+            if routes.get(peer).is_some_and(|current| Arc::ptr_eq(current, route)) {
+                routes.remove(peer);
+            }
+        }
+    }
+}
+
+/// The most an upload may grow to, and why
+#[derive(Debug, Clone, Copy)]
+struct UploadLimit {
+    bytes: u64,
+    reason: &'static str,
+}
+
+/// What a served directory holds: its size when it was last measured, plus
+/// what running uploads have received since
+#[derive(Debug, Default)]
+struct DirectoryUsage {
+    bytes: u64,
+    uploads: usize,
+}
+
+/// The bytes one upload has charged against the quotas. Every upload charges
+/// the same ledgers block by block, so parallel uploads cannot overshoot a
+/// quota together. Dropped before `keep`, it hands its bytes back.
+struct UploadCharge<'a> {
+    server: &'a TFTPServer,
+    root: PathBuf,
+    client: IpAddr,
+    bytes: u64,
+    kept: bool,
+    /// Whether the upload is counted in `directory_usage`, which only
+    /// servers with a `quota` do
+    registered: bool,
+}
+
+impl<'a> UploadCharge<'a> {
+    fn new(server: &'a TFTPServer, root: &Path, client: IpAddr) -> Self {
+        let registered = server.quota.is_some();
+        if registered {
+            // Measured only while idle, when no partial files are lying
+            // around. The walk runs unlocked so that running uploads on
+            // other roots are not held up, and its result is dropped if an
+            // upload started here in the meantime.
+            let idle = |directories: &HashMap<PathBuf, DirectoryUsage>| {
+                directories.get(root).is_none_or(|usage| usage.uploads == 0)
+            };
+            let measured = idle(&server.directory_usage.lock().unwrap()).then(|| directory_usage(root));
+
+            let mut directories = server.directory_usage.lock().unwrap();
+            if let Some(bytes) = measured
+                && idle(&directories)
+            {
+                directories.entry(root.to_path_buf()).or_default().bytes = bytes;
+            }
+            directories.entry(root.to_path_buf()).or_default().uploads += 1;
+        }
+
+        UploadCharge {
+            server,
+            root: root.to_path_buf(),
+            client,
+            bytes: 0,
+            kept: false,
+            registered,
+        }
+    }
+
+    /// The tightest of the size limit and quotas left for this upload, or
+    /// `None` when none is configured
+    fn remaining(&self) -> Option<UploadLimit> {
+        let server = self.server;
+        let mut limits = Vec::new();
+
+        if let Some(max) = server.max_upload_size {
+            limits.push(UploadLimit {
+                bytes: max.saturating_sub(self.bytes),
+                reason: "File exceeds upload size limit",
+            });
+        }
+        if let Some(quota) = server.quota {
+            let used = server.directory_usage.lock().unwrap().get(&self.root).map_or(0, |usage| usage.bytes);
+            limits.push(UploadLimit {
+                bytes: quota.saturating_sub(used),
+                reason: "Upload directory quota exceeded",
+            });
+        }
+        if let Some(quota) = server.client_quota {
+            let used = server.client_uploads.lock().unwrap().get(&self.client).copied().unwrap_or(0);
+            limits.push(UploadLimit {
+                bytes: quota.saturating_sub(used),
+                reason: "Client upload quota exceeded",
+            });
+        }
+
+        limits.into_iter().min_by_key(|limit| limit.bytes)
+    }
+
+    /// Charges `bytes` more to the upload, or fails with the limit they
+    /// would go over
+    fn charge(&mut self, bytes: u64) -> Result<(), TransferError> {
+        let server = self.server;
+        let exceeded = |reason: &str| Err(TransferError::Protocol(ERROR_DISK_FULL, reason.to_string()));
+
+        if server.max_upload_size.is_some_and(|max| self.bytes + bytes > max) {
+            return exceeded("File exceeds upload size limit");
+        }
+
+        // Both ledgers stay locked from check to charge, always in this order
+        let mut directories = server.directory_usage.lock().unwrap();
+        let mut clients = server.client_uploads.lock().unwrap();
+        let directory = if self.registered { directories.get_mut(&self.root) } else { None };
+        if let (Some(quota), Some(usage)) = (server.quota, directory.as_deref())
+            && usage.bytes + bytes > quota
+        {
+            return exceeded("Upload directory quota exceeded");
+        }
+        let client = clients.entry(self.client).or_insert(0);
+        if server.client_quota.is_some_and(|quota| *client + bytes > quota) {
+            return exceeded("Client upload quota exceeded");
+        }
+
+        if let Some(usage) = directory {
+            usage.bytes += bytes;
+        }
+        *client += bytes;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Keeps the charged bytes once the upload is in place
+    fn keep(&mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for UploadCharge<'_> {
+    fn drop(&mut self) {
+        let mut directories = self.server.directory_usage.lock().unwrap();
+        let mut clients = self.server.client_uploads.lock().unwrap();
+        if self.registered
+            && let Some(usage) = directories.get_mut(&self.root)
+        {
+            usage.uploads -= 1;
+            if !self.kept {
+                usage.bytes = usage.bytes.saturating_sub(self.bytes);
+            }
+        }
+        if !self.kept
+            && let Some(used) = clients.get_mut(&self.client)
+        {
+            *used = used.saturating_sub(self.bytes);
+        }
+    }
+}
+
+/// Where an upload lands: the directory it goes in, opened beneath the
+/// served directory, and its name there. Everything after the lookup works
+/// on the open directory, so a path swapped for a symlink mid-upload cannot
+/// redirect it.
+#[derive(Debug)]
+struct UploadTarget {
+    directory: File,
+    name: CString,
+}
+
+impl UploadTarget {
+    fn open(root: &Path, filename: &str) -> std::io::Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(filename);
+        let name = path
+            .file_name()
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EISDIR))?;
+        let directory = open_beneath(
+            root,
+            path.parent().unwrap_or(Path::new("")),
+            libc::O_RDONLY | libc::O_DIRECTORY,
+        )?;
+
+        Ok(UploadTarget {
+            directory,
+            name: CString::new(name.as_bytes())?,
+        })
+    }
+
+    fn exists(&self) -> bool {
+        use std::os::unix::io::AsRawFd;
+
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::fstatat(
+                self.directory.as_raw_fd(),
+                self.name.as_ptr(),
+                &mut stat,
+                libc::AT_SYMLINK_NOFOLLOW,
+            ) == 0
+        }
+    }
+}
+
+/// An upload being written to a hidden file next to its target. The target
+/// is only replaced once the upload is complete, and dropping an unfinished
+/// upload removes the partial file.
+#[derive(Debug)]
+struct PartialUpload {
+    file: File,
+    directory: File,
+    name: CString,
+    committed: bool,
+}
+
+impl PartialUpload {
+    fn create(target: &UploadTarget) -> std::io::Result<Self> {
+        static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let name = format!(".{}.{}-{}.part", target.name.to_string_lossy(), std::process::id(), id);
+        let name = CString::new(name)?;
+        let file = open_at(&target.directory, &name, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL)?;
+
+        Ok(PartialUpload {
+            file,
+            directory: target.directory.try_clone()?,
+            name,
+            committed: false,
+        })
+    }
+
+    /// Flushes the upload to disk and moves it into place. Without
+    /// `overwrite`, an existing target fails the upload with AlreadyExists.
+    fn commit(&mut self, target: &UploadTarget, overwrite: bool) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        self.file.sync_all()?;
+
+        let directory = self.directory.as_raw_fd();
+        if overwrite {
+            check_os(unsafe { libc::renameat(directory, self.name.as_ptr(), directory, target.name.as_ptr()) })?;
+        } else {
+            // Linking fails if the target appeared while we were receiving
+            check_os(unsafe { libc::linkat(directory, self.name.as_ptr(), directory, target.name.as_ptr(), 0) })?;
+            unsafe { libc::unlinkat(directory, self.name.as_ptr(), 0) };
+        }
+        self.committed = true;
+
+        // Make the new directory entry durable too
+        let _ = self.directory.sync_all();
+        Ok(())
+    }
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        if !self.committed {
+            unsafe { libc::unlinkat(self.directory.as_raw_fd(), self.name.as_ptr(), 0) };
+        }
+    }
+}
+
+/// Identifies a live session: client address, filename and request opcode
+type TransferKey = (SocketAddr, String, u16);
+
+/// Keeps a session registered in `active_transfers` until it is dropped
+struct TransferGuard {
+    transfers: Arc<Mutex<HashMap<TransferKey, Arc<AtomicBool>>>>,
+    key: TransferKey,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.transfers.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// What to do with requests for absolute paths such as `/boot/pxelinux.0`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AbsolutePaths {
+    /// Refuse them with an access violation
+    Reject,
+    /// Serve them relative to the served directory
+    Strip,
+}
+
+/// The clients a `--client-root` applies to
+#[derive(Debug, Clone, Copy)]
+enum ClientMatch {
+    Network(Cidr),
+    /// Looked up in the ARP table, so only for IPv4 clients on a local link
+    Mac([u8; 6]),
+}
+
+impl std::fmt::Display for ClientMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientMatch::Network(network) => write!(f, "{}/{}", network.network, network.prefix_len),
+            ClientMatch::Mac(address) => write!(f, "{}", format_mac(*address).replace('-', ":")),
+        }
+    }
+}
+
+/// A root directory for some clients: `CIDR=DIR` or `MAC=DIR`. `{mac}` in
+/// the directory stands for the client's MAC address.
+#[derive(Debug, Clone)]
+struct ClientRoot {
+    clients: ClientMatch,
+    directory: String,
+}
+
+impl ClientRoot {
+    fn parse(value: &str) -> Option<Self> {
+        let (clients, directory) = value.split_once('=')?;
+        let clients = match parse_mac(clients) {
+            Some(address) => ClientMatch::Mac(address),
+            None => ClientMatch::Network(Cidr::parse(clients)?),
+        };
+        if directory.is_empty() {
+            return None;
+        }
+        let directory = env::current_dir().ok()?.join(directory);
+
+        Some(ClientRoot {
+            clients,
+            directory: directory.to_str()?.to_string(),
+        })
+    }
+}
+
+/// Which request types the server accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    ReadWrite,
+    ReadOnly,
+    WriteOnly,
+}
+
+/// An IPv4 or IPv6 network in CIDR notation
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    /// Parses `10.1.0.0/16`, `fd00::/8`, or a bare address for a single host
+    fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, len)) => (addr.parse::<IpAddr>().ok()?, Some(len.parse::<u32>().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(bits);
+        (prefix_len <= bits).then_some(Cidr {
+            network: addr,
+            prefix_len,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// One line of an access control list:
+/// `allow|deny CIDR [read|write|read,write] [/path/prefix]`
+#[derive(Debug, Clone)]
+struct AclRule {
+    allow: bool,
+    network: Cidr,
+    read: bool,
+    write: bool,
+    /// Only requests for files under this path match
+    prefix: Option<PathBuf>,
+    /// Where the rule came from, for logging
+    source: String,
+}
+
+impl AclRule {
+    fn parse(line: &str, source: String) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let allow = match words.next() {
+            Some("allow") => true,
+            Some("deny") => false,
+            _ => return Err("expected allow or deny".to_string()),
+        };
+        let network = words
+            .next()
+            .and_then(Cidr::parse)
+            .ok_or_else(|| "expected an address or network, such as 10.1.0.0/16".to_string())?;
+
+        let mut rule = AclRule {
+            allow,
+            network,
+            read: true,
+            write: true,
+            prefix: None,
+            source,
+        };
+        let mut permissions_given = false;
+        for word in words {
+            if word.starts_with('/') {
+                if rule.prefix.is_some() {
+                    return Err("more than one path".to_string());
+                }
+                rule.prefix = Some(PathBuf::from(word.trim_start_matches('/')));
+                continue;
+            }
+            // Both permissions go in one field, as read,write
+            if permissions_given {
+                return Err("more than one permissions field".to_string());
+            }
+            permissions_given = true;
+            rule.read = false;
+            rule.write = false;
+            for permission in word.split(',') {
+                match permission {
+                    "read" => rule.read = true,
+                    "write" => rule.write = true,
+                    _ => return Err(format!("unknown permission '{}'", permission)),
+                }
+            }
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, client: IpAddr, opcode: u16, filename: &str) -> bool {
+        let kind_matches = if opcode == WRQ { self.write } else { self.read };
+        kind_matches
+            && self.network.contains(client)
+            && self.prefix.as_ref().is_none_or(|prefix| path_within(filename, prefix))
+    }
+}
+
+/// What a map file rule does with a filename its pattern matches
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MapAction {
+    /// Replace every match and carry on with the next rule
+    Replace,
+    /// Replace the first match and stop
+    Rewrite,
+    /// Refuse the request
+    Reject,
+}
+
+/// One line of a map file:
+/// `replace|rewrite REGEX REPLACEMENT [CIDR]` or `reject REGEX [CIDR]`
+#[derive(Debug, Clone)]
+struct MapRule {
+    action: MapAction,
+    pattern: regex::Regex,
+    /// May refer to capture groups as `$1` or `${name}`
+    replacement: String,
+    /// Only requests from this network match
+    client: Option<Cidr>,
+    /// Where the rule came from, for logging
+    source: String,
+}
+
+impl MapRule {
+    fn parse(line: &str, source: String) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let action = match words.first() {
+            Some(&"replace") => MapAction::Replace,
+            Some(&"rewrite") => MapAction::Rewrite,
+            Some(&"reject") => MapAction::Reject,
+            _ => return Err("expected replace, rewrite or reject".to_string()),
+        };
+        let pattern = words.get(1).ok_or_else(|| "expected a regular expression".to_string())?;
+        let pattern = regex::Regex::new(pattern).map_err(|e| e.to_string())?;
+
+        let (replacement, rest) = match action {
+            MapAction::Reject => (String::new(), &words[2..]),
+            _ => {
+                let replacement = words.get(2).ok_or_else(|| "expected a replacement".to_string())?;
+                (replacement.to_string(), &words[3..])
+            }
+        };
+        let client = match rest {
+            [] => None,
+            [network] => Some(Cidr::parse(network).ok_or_else(|| format!("invalid client network '{}'", network))?),
+            _ => return Err("too many fields".to_string()),
+        };
+
+        Ok(MapRule {
+            action,
+            pattern,
+            replacement,
+            client,
+            source,
+        })
+    }
+}
+
+/// Address families the server listens on
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IpMode {
+    /// One `[::]` socket that also accepts IPv4 (IPV6_V6ONLY off)
+    DualStack,
+    Ipv4Only,
+    /// `[::]` with IPV6_V6ONLY on
+    Ipv6Only,
+}
+
+impl IpMode {
+    fn describe(self) -> &'static str {
+        match self {
+            IpMode::DualStack => "IPv4 and IPv6",
+            IpMode::Ipv4Only => "IPv4 only",
+            IpMode::Ipv6Only => "IPv6 only",
+        }
+    }
+}
+
+/// Option/value pairs as they appear in a request (RFC 2347)
+type RequestOptions = Vec<(String, String)>;
+
+/// Transfer modes from RFC 1350. The obsolete "mail" mode is not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum TransferMode {
+    Octet,
+    Netascii,
+}
+
+impl TransferMode {
+    fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "octet" => Some(TransferMode::Octet),
+            "netascii" => Some(TransferMode::Netascii),
+            _ => None,
+        }
+    }
+}
+
+/// Why a request or transfer could not go ahead. Each variant maps onto the
+/// RFC 1350 error code the peer is told before the session is abandoned.
+#[derive(Debug)]
+enum TransferError {
+    /// A local I/O failure
+    Io(std::io::Error),
+    /// The peer stopped answering
+    Timeout,
+    /// The peer sent an ERROR packet, which must not be answered
+    Peer(u16, String),
+    /// We refuse to go on, with the code and message to send
+    Protocol(u16, String),
+}
+
+impl TransferError {
+    fn code(&self) -> u16 {
+        use std::io::ErrorKind;
+
+        match self {
+            TransferError::Io(e) => match e.kind() {
+                ErrorKind::NotFound => ERROR_FILE_NOT_FOUND,
+                ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => ERROR_ACCESS_VIOLATION,
+                ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge => {
+                    ERROR_DISK_FULL
+                }
+                ErrorKind::AlreadyExists => ERROR_FILE_EXISTS,
+                _ => ERROR_NOT_DEFINED,
+            },
+            TransferError::Timeout => ERROR_NOT_DEFINED,
+            TransferError::Peer(code, _) | TransferError::Protocol(code, _) => *code,
+        }
+    }
+
+    /// Text for the ERROR packet. Local details such as paths stay in our log.
+    fn peer_message(&self) -> String {
+        match self {
+            TransferError::Io(_) if self.code() == ERROR_NOT_DEFINED => "Server I/O error".to_string(),
+            TransferError::Io(_) => error_description(self.code()).to_string(),
+            TransferError::Timeout => "Transfer timed out".to_string(),
+            TransferError::Peer(_, msg) | TransferError::Protocol(_, msg) => msg.clone(),
+        }
+    }
+
+    fn notifies_peer(&self) -> bool {
+        !matches!(self, TransferError::Peer(..))
+    }
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::Io(e) => write!(f, "{}", e),
+            TransferError::Timeout => write!(
+                f,
+                "Transfer failed after {} retries - network unstable",
+                MAX_RETRIES
+            ),
+            TransferError::Peer(code, msg) => write!(
+                f,
+                "Client error {} ({}): {}",
+                code,
+                error_description(*code),
+                msg
+            ),
+            TransferError::Protocol(_, msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+impl From<std::io::Error> for TransferError {
+    fn from(e: std::io::Error) -> Self {
+        TransferError::Io(e)
+    }
+}
+
+fn error_description(code: u16) -> &'static str {
+    match code {
+        ERROR_FILE_NOT_FOUND => "File not found",
+        ERROR_ACCESS_VIOLATION => "Access violation",
+        ERROR_DISK_FULL => "Disk full or allocation exceeded",
+        ERROR_ILLEGAL_OPERATION => "Illegal TFTP operation",
+        ERROR_UNKNOWN_TID => "Unknown transfer ID",
+        ERROR_FILE_EXISTS => "File already exists",
+        ERROR_NO_SUCH_USER => "No such user",
+        ERROR_OPTION_NEGOTIATION => "Option negotiation failed",
+        _ => "Not defined",
+    }
+}
+
+// Reads the ERROR packet a peer sent us
+fn peer_error(packet: &[u8]) -> TransferError {
+    let code = u16::from_be_bytes([packet[2], packet[3]]);
+    let text = &packet[4..];
+    let text = text.strip_suffix(&[0]).unwrap_or(text);
+    TransferError::Peer(code, String::from_utf8_lossy(text).into_owned())
+}
+
+/// Per-session counters, reported when a transfer ends, failed or not
+#[derive(Debug, Default)]
+struct TransferStats {
+    /// Packets from other senders answered with error 5
+    unknown_tids: u64,
+    /// Duplicate and out-of-window ACKs ignored instead of triggering a
+    /// resend
+    duplicate_acks: u64,
+    /// Other unexpected packets from the peer, also ignored
+    ignored_packets: u64,
+}
+
+impl TransferStats {
+    fn report(&self, tag: &str, filename: &str) {
+        if self.unknown_tids > 0 {
+            println!(
+                "[INFO] {}{}: answered {} packet(s) from unknown transfer IDs",
+                tag, filename, self.unknown_tids
+            );
+        }
+        if self.duplicate_acks > 0 {
+            println!(
+                "[INFO] {}{}: suppressed {} duplicate ACK(s)",
+                tag, filename, self.duplicate_acks
+            );
+        }
+        if self.ignored_packets > 0 {
+            println!(
+                "[INFO] {}{}: ignored {} unexpected packet(s)",
+                tag, filename, self.ignored_packets
+            );
+        }
+    }
+}
+
+/// What follows block 65535 on transfers too large for 16-bit block numbers
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rollover {
+    Zero,
+    One,
+    /// Refuse such transfers, for clients that abort on a wrap
+    Refuse,
+}
+
+impl Rollover {
+    /// Parses the --rollover command line value
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "0" => Some(Rollover::Zero),
+            "1" => Some(Rollover::One),
+            "none" => Some(Rollover::Refuse),
+            _ => None,
+        }
+    }
+
+    /// Maps a block's position in the transfer (starting at 1) to the number
+    /// sent on the wire, or `None` when it cannot be numbered
+    fn wire_block(self, block: u64) -> Option<u16> {
+        if block <= u16::MAX as u64 {
+            return Some(block as u16);
+        }
+
+        match self {
+            Rollover::Zero => Some(block as u16),
+            Rollover::One => Some((1 + (block - 65536) % 65535) as u16),
+            Rollover::Refuse => None,
+        }
+    }
+}
+
+/// Per-transfer settings: the request mode plus everything agreed with the
+/// client through RFC 2347 option negotiation
+#[derive(Debug)]
+struct TransferOptions {
+    mode: TransferMode,
+    /// Payload bytes per DATA packet (RFC 2348)
+    blksize: usize,
+    /// Blocks sent before waiting for an ACK (RFC 7440)
+    windowsize: usize,
+    /// Total transfer size in bytes, when known (RFC 2349)
+    tsize: Option<u64>,
+    /// Base retransmission timer (RFC 2349 timeout, or utimeout in ms)
+    timeout: Duration,
+    /// Block number that follows 65535
+    rollover: Rollover,
+    /// Whether the client asked to join a multicast session (RFC 2090)
+    multicast: bool,
+    /// Option/value pairs to echo back in the OACK, in request order
+    acknowledged: RequestOptions,
+    /// Set to stop the session early (see `register_transfer`)
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            mode: TransferMode::Octet,
+            blksize: PACKET_SIZE,
+            windowsize: 1,
+            tsize: None,
+            timeout: Duration::from_millis(INITIAL_TIMEOUT_MS),
+            rollover: Rollover::Zero,
+            multicast: false,
+            acknowledged: Vec::new(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl TransferOptions {
+    /// Builds the OACK packet, or `None` when no option was accepted and the
+    /// transfer should start the plain RFC 1350 way
+    fn oack_packet(&self) -> Option<Vec<u8>> {
+        if self.acknowledged.is_empty() {
+            return None;
+        }
+        Some(oack_packet(&self.acknowledged))
+    }
+
+    fn check_cancelled(&self) -> Result<(), TransferError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(TransferError::Protocol(
+                ERROR_NOT_DEFINED,
+                "Transfer cancelled".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A client taking part in a multicast session (RFC 2090)
+#[derive(Debug)]
+struct MulticastClient {
+    addr: SocketAddr,
+    /// The options acknowledged to this client. The multicast value depends
+    /// on whether it is the master, so it is filled in when the OACK is sent.
+    acknowledged: RequestOptions,
+}
+
+impl MulticastClient {
+    fn oack_packet(&self, group: SocketAddrV4, master: bool) -> Vec<u8> {
+        let pairs: RequestOptions = self
+            .acknowledged
+            .iter()
+            .map(|(name, value)| {
+                if name == "multicast" {
+                    let value = format!("{},{},{}", group.ip(), group.port(), master as u8);
+                    (name.clone(), value)
+                } else {
+                    (name.clone(), value.clone())
+                }
+            })
+            .collect();
+        oack_packet(&pairs)
+    }
+}
+
+/// Shared state of a running multicast session
+#[derive(Debug)]
+struct MulticastSession {
+    group: SocketAddrV4,
+    /// Clients that asked to join since the session loop last looked
+    joining: Vec<MulticastClient>,
+}
+
+/// Clients share a multicast session when they read the same file with the
+/// same mode and block size, through the same local address and virtual
+/// server
+type MulticastKey = (PathBuf, TransferMode, usize, Ipv4Addr, Option<String>);
+
+/// Converts a local file to netascii on the fly: LF becomes CR LF and a bare
+/// CR becomes CR NUL
+struct NetasciiReader<R> {
+    inner: R,
+    encoded: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> NetasciiReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            encoded: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for NetasciiReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.encoded.len() {
+            let mut raw = [0; 4096];
+            let bytes_read = self.inner.read(&mut raw)?;
+            if bytes_read == 0 {
+                return Ok(0);
+            }
+
+            self.encoded.clear();
+            self.pos = 0;
+            for &byte in &raw[..bytes_read] {
+                match byte {
+                    b'\n' => self.encoded.extend_from_slice(b"\r\n"),
+                    b'\r' => self.encoded.extend_from_slice(b"\r\0"),
+                    _ => self.encoded.push(byte),
+                }
+            }
+        }
+
+        let count = std::cmp::min(buf.len(), self.encoded.len() - self.pos);
+        buf[..count].copy_from_slice(&self.encoded[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+/// Reverses the netascii encoding of an upload. A CR at the end of one block
+/// is held back until the next block shows what follows it.
+#[derive(Default)]
+struct NetasciiDecoder {
+    pending_cr: bool,
+}
+
+impl NetasciiDecoder {
+    fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::with_capacity(data.len());
+
+        for &byte in data {
+            if self.pending_cr {
+                self.pending_cr = false;
+                match byte {
+                    b'\n' => {
+                        decoded.push(b'\n');
+                        continue;
+                    }
+                    0 => {
+                        decoded.push(b'\r');
+                        continue;
+                    }
+                    // Not valid netascii, keep the CR rather than lose data
+                    _ => decoded.push(b'\r'),
+                }
+            }
+
+            if byte == b'\r' {
+                self.pending_cr = true;
+            } else {
+                decoded.push(byte);
+            }
+        }
+
+        decoded
+    }
+
+    // Whatever is still held back once the last block has arrived
+    fn finish(&self) -> &'static [u8] {
+        if self.pending_cr { b"\r" } else { b"" }
+    }
+}
+
+// Size of a file once converted to netascii, as reported through tsize
+fn netascii_size(file: &File) -> std::io::Result<u64> {
+    use std::os::unix::fs::FileExt;
+
+    let mut buffer = [0; 64 * 1024];
+    let mut offset = 0u64;
+    let mut size = 0u64;
+
+    // Positioned reads leave the file offset at the start for the transfer
+    loop {
+        let bytes_read = file.read_at(&mut buffer, offset)?;
+        if bytes_read == 0 {
+            return Ok(size);
+        }
+        offset += bytes_read as u64;
+        size += bytes_read as u64;
+        size += buffer[..bytes_read]
+            .iter()
+            .filter(|&&byte| byte == b'\n' || byte == b'\r')
+            .count() as u64;
+    }
 }
 
 struct ProgressBar {
@@ -107,10 +1148,11 @@ impl ProgressBar {
         let _ = stderr().flush();
     }
 
-    fn finish(&mut self, operation: &str, bytes: u64, addr: std::net::IpAddr) {
+    fn finish(&mut self, tag: &str, operation: &str, bytes: u64, addr: std::net::IpAddr) {
         eprintln!();
         println!(
-            "[INFO] {} completed: {} ({}) {}",
+            "[INFO] {}{} completed: {} ({}) {}",
+            tag,
             operation,
             self.filename,
             format_size(bytes),
@@ -122,11 +1164,6 @@ impl ProgressBar {
         );
     }
 
-    fn error(&mut self, message: &str) {
-        eprintln!();
-        println!("[ERROR] {}: {}", self.filename, message);
-    }
-
     fn retry_info(&mut self, retry: usize, max_retries: usize) {
         eprint!("\r\x1B[K[RETRY {}/{}] {} - Network timeout, retrying...", 
                 retry, max_retries, self.filename);
@@ -188,7 +1225,7 @@ fn get_terminal_width_stty() -> Option<usize> {
 
     let output = Command::new("stty").arg("size").output().ok()?;
     let size_str = String::from_utf8(output.stdout).ok()?;
-    let parts: Vec<&str> = size_str.trim().split_whitespace().collect();
+    let parts: Vec<&str> = size_str.split_whitespace().collect();
 
     if parts.len() >= 2 {
         parts[1].parse::<usize>().ok().filter(|&w| w > 0)
@@ -197,11 +1234,28 @@ fn get_terminal_width_stty() -> Option<usize> {
     }
 }
 
-// Adaptive timeout calculation
-fn calculate_timeout(retry: usize) -> Duration {
+// Reads until the buffer is full or EOF, so a short read in the middle of
+// a file is never mistaken for the final block
+fn read_block(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// Adaptive timeout calculation, backing off from the session's base timer.
+// A base above MAX_TIMEOUT_MS (negotiated by the client) is used as-is.
+fn calculate_timeout(base: Duration, retry: usize) -> Duration {
+    let base_ms = base.as_millis() as u64;
     let timeout_ms = std::cmp::min(
-        INITIAL_TIMEOUT_MS * (2_u64.pow(retry as u32)),
-        MAX_TIMEOUT_MS
+        base_ms.saturating_mul(2_u64.saturating_pow(retry as u32)),
+        std::cmp::max(base_ms, MAX_TIMEOUT_MS)
     );
     Duration::from_millis(timeout_ms)
 }
@@ -210,24 +1264,66 @@ impl TFTPServer {
     fn new(port: u16, directory: Option<PathBuf>) -> Self {
         let dir = directory.unwrap_or_else(|| env::current_dir().unwrap());
         TFTPServer {
+            name: None,
             port,
             directory: dir,
+            ip_mode: IpMode::DualStack,
+            listen: Vec::new(),
+            interface: None,
+            port_range: None,
+            next_port: Arc::new(AtomicU32::new(0)),
+            single_port: false,
+            listener: None,
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            access: Access::ReadWrite,
+            acl: None,
+            map: None,
+            absolute_paths: AbsolutePaths::Reject,
+            client_roots: Arc::new(Vec::new()),
+            no_overwrite: false,
+            max_upload_size: None,
+            quota: None,
+            client_quota: None,
+            client_uploads: Arc::new(Mutex::new(HashMap::new())),
+            directory_usage: Arc::new(Mutex::new(HashMap::new())),
+            rollover: Rollover::Zero,
+            max_blksize: MAX_BLKSIZE,
+            max_windowsize: MAX_WINDOWSIZE,
+            multicast_base: None,
+            multicast_sessions: Arc::new(Mutex::new(HashMap::new())),
             active_transfers: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    /// Marks log lines with the virtual server they concern, as `[name] `
+    fn tag(&self) -> String {
+        self.name.as_ref().map_or_else(String::new, |name| format!("[{}] ", name))
+    }
+
     fn clear_terminal(&self) {
         print!("\x1B[2J\x1B[1;1H");
         let _ = stdout().flush();
     }
 
-    fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.clear_terminal();
+    /// Binds the listeners of every virtual server, then serves them all
+    /// until they stop. Nothing is served unless every listener could be
+    /// bound.
+    fn start(servers: &[TFTPServer]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut bound = Vec::new();
+        for server in servers {
+            let listeners = server.bind_listeners().map_err(|e| match &server.name {
+                Some(name) => std::io::Error::new(e.kind(), format!("virtual server {}: {}", name, e)),
+                None => e,
+            })?;
+            for socket in &listeners {
+                // Optimize socket for better WiFi performance
+                server.optimize_socket(socket)?;
+                enable_pktinfo(socket)?;
+            }
+            bound.push((server, listeners));
+        }
 
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", self.port))?;
-
-        // Optimize socket for better WiFi performance
-        self.optimize_socket(&socket)?;
+        servers[0].clear_terminal();
 
         println!(" _    __ _             _        _ _                  ");
         println!("| |  / _| |           | |      | (_)                 ");
@@ -238,23 +1334,106 @@ impl TFTPServer {
         println!("            | |                                      ");
         println!("            |_|                                      ");
         println!("{}", "=".repeat(53));
-        println!("[-] TFTP Server started on port {}", self.port);
-        println!("[-] Serving files from: {}", self.directory.display());
-        println!("[-] Server IP: {}", self.get_local_ip());
+        println!("[-] TFTP Server started");
+        for (server, listeners) in &bound {
+            server.print_banner(listeners)?;
+        }
         println!("[-] Waiting for requests... (Ctrl+C to stop)");
         println!("{}", "-".repeat(53));
 
-        let mut buffer = [0; 1024];
+        let handles: Vec<_> = bound
+            .into_iter()
+            .flat_map(|(server, listeners)| {
+                listeners.into_iter().map(move |socket| {
+                    let server = server.clone();
+                    thread::spawn(move || server.serve(socket))
+                })
+            })
+            .collect();
+        for handle in handles {
+            let _ = handle.join();
+        }
+
+        println!("\n[INFO] Server stopped.");
+        Ok(())
+    }
+
+    /// The startup lines describing one virtual server
+    fn print_banner(&self, listeners: &[UdpSocket]) -> std::io::Result<()> {
+        if let Some(name) = &self.name {
+            println!("[-] Virtual server: {}", name);
+        }
+        for socket in listeners {
+            let addr = socket.local_addr()?;
+            let mut details = Vec::new();
+            if addr.ip().is_unspecified() {
+                details.push(match addr {
+                    SocketAddr::V4(_) => IpMode::Ipv4Only.describe(),
+                    SocketAddr::V6(_) if self.ip_mode == IpMode::Ipv6Only => IpMode::Ipv6Only.describe(),
+                    SocketAddr::V6(_) => IpMode::DualStack.describe(),
+                });
+            }
+            if let Some(interface) = &self.interface {
+                details.push(interface);
+            }
+            if details.is_empty() {
+                println!("[-] Listening on: {}", addr);
+            } else {
+                println!("[-] Listening on: {} ({})", addr, details.join(", "));
+            }
+        }
+        println!("[-] Serving files from: {}", self.directory.display());
+        for root in self.client_roots.iter() {
+            println!("[-] Serving {} from: {}", root.clients, root.directory);
+        }
+        if let Some((first, last)) = self.port_range {
+            println!("[-] Transfer ports: {}-{}", first, last);
+        }
+        Ok(())
+    }
+
+    /// Receives requests on one listening socket, handing each to its own
+    /// thread
+    fn serve(&self, socket: UdpSocket) {
+        let socket = Arc::new(socket);
+        let mut server = self.clone();
+        if self.single_port {
+            server.listener = Some(Arc::clone(&socket));
+        }
+        let mut buffer = vec![0; MAX_BLKSIZE + 4];
 
         loop {
-            match socket.recv_from(&mut buffer) {
-                Ok((size, addr)) => {
+            match recv_request(&socket, &mut buffer) {
+                Ok((size, addr, local)) => {
+                    // IPv4 clients reach the dual-stack socket as ::ffff:a.b.c.d
+                    let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                    // Replies come from the address the request was sent to,
+                    // so multi-homed hosts answer from the one the client knows
+                    let local = local.unwrap_or_else(|| unspecified_for(addr));
                     let data = buffer[..size].to_vec();
-                    let server_clone = self.clone();
+
+                    // In single-port mode everything but a new request
+                    // belongs to the client's running session. Without one
+                    // the sender has an unknown transfer ID.
+                    if self.single_port && size >= 2 {
+                        let opcode = u16::from_be_bytes([data[0], data[1]]);
+                        if opcode != RRQ && opcode != WRQ {
+                            if let Some(route) = self.routes.lock().unwrap().get(&addr) {
+                                let _ = route.send(data);
+                            } else if opcode != ERROR {
+                                println!("[INFO] {}Packet from unknown transfer ID {}, sent error 5", self.tag(), addr);
+                                let packet = error_packet(ERROR_UNKNOWN_TID, "Unknown transfer ID");
+                                let _ = send_from_listener(&socket, &packet, addr);
+                            }
+                            continue;
+                        }
+                    }
+
+                    let server_clone = server.clone();
 
                     thread::spawn(move || {
-                        if let Err(e) = server_clone.handle_request(&data, addr) {
-                            eprintln!("[ERROR] Error handling request from {}: {}", addr, e);
+                        if let Err(e) = server_clone.handle_request(&data, addr, local) {
+                            eprintln!("[ERROR] {}Error handling request from {}: {}", server_clone.tag(), addr, e);
                         }
                     });
                 }
@@ -262,17 +1441,99 @@ impl TFTPServer {
                     if e.kind() == std::io::ErrorKind::Interrupted {
                         break;
                     }
-                    eprintln!("[ERROR] Error receiving data: {}", e);
+                    eprintln!("[ERROR] {}Error receiving data: {}", self.tag(), e);
                     thread::sleep(Duration::from_millis(100)); // Brief pause on error
                 }
             }
         }
+    }
 
-        println!("\n[INFO] Server stopped.");
-        Ok(())
+    /// Binds every `--listen` address, or the default listener on `port`
+    /// when none was given
+    fn bind_listeners(&self) -> std::io::Result<Vec<UdpSocket>> {
+        if self.listen.is_empty() {
+            return Ok(vec![self.bind_listener(self.port)?]);
+        }
+        self.listen
+            .iter()
+            .map(|&addr| bind_udp(addr, self.ip_mode == IpMode::Ipv6Only, self.interface.as_deref()))
+            .collect()
     }
 
-    fn optimize_socket(&self, socket: &UdpSocket) -> Result<(), Box<dyn std::error::Error>> {
+    /// Binds the listening socket for `port` according to `ip_mode`. A host
+    /// without IPv6 support still gets an IPv4 listener in dual-stack mode.
+    fn bind_listener(&self, port: u16) -> std::io::Result<UdpSocket> {
+        let device = self.interface.as_deref();
+        let ipv4 = (Ipv4Addr::UNSPECIFIED, port).into();
+        let ipv6 = (Ipv6Addr::UNSPECIFIED, port).into();
+
+        match self.ip_mode {
+            IpMode::Ipv4Only => bind_udp(ipv4, false, device),
+            IpMode::Ipv6Only => bind_udp(ipv6, true, device),
+            IpMode::DualStack => match bind_udp(ipv6, false, device) {
+                Err(e) if e.raw_os_error() == Some(libc::EAFNOSUPPORT) => bind_udp(ipv4, false, device),
+                result => result,
+            },
+        }
+    }
+
+    /// Binds a transfer or error socket to the local address a request
+    /// arrived on, on a port from `port_range` when one is configured
+    fn bind_reply_socket(&self, local: SocketAddr) -> std::io::Result<UdpSocket> {
+        let device = self.interface.as_deref();
+        let Some((first, last)) = self.port_range else {
+            return bind_udp(local, false, device);
+        };
+
+        // Searching on from the last port handed out keeps a port that was
+        // just released from being reused straight away
+        let count = (last - first) as u32 + 1;
+        let start = self.next_port.fetch_add(1, Ordering::Relaxed);
+        for i in 0..count {
+            let mut addr = local;
+            addr.set_port(first + (start.wrapping_add(i) % count) as u16);
+            match bind_udp(addr, false, device) {
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+                result => return result,
+            }
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("No free port in transfer port range {}-{}", first, last),
+        ))
+    }
+
+    /// Sets up the channel a session talks to its client over: a socket of
+    /// its own, or in single-port mode a route from the listening socket. A
+    /// used-up port range refuses the session with its own error message.
+    fn open_transport(&self, addr: SocketAddr, local: SocketAddr) -> Result<Transport, TransferError> {
+        if let Some(listener) = &self.listener {
+            let (sender, inbox) = mpsc::channel();
+            let route = Arc::new(sender);
+            self.routes.lock().unwrap().insert(addr, Arc::clone(&route));
+            return Ok(Transport::Shared {
+                socket: Arc::clone(listener),
+                inbox,
+                peer: addr,
+                route,
+                routes: Arc::clone(&self.routes),
+            });
+        }
+
+        let socket = match self.bind_reply_socket(local) {
+            Ok(socket) => socket,
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && self.port_range.is_some() => {
+                println!("[ERROR] {}{} (request from {})", self.tag(), e, addr);
+                return Err(TransferError::Protocol(ERROR_NOT_DEFINED, "No free transfer port".to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.optimize_socket(&socket)?;
+        Ok(Transport::Socket(socket))
+    }
+
+    fn optimize_socket(&self, socket: &UdpSocket) -> std::io::Result<()> {
         // Increase socket buffer sizes for better WiFi performance
         const BUFFER_SIZE: usize = 256 * 1024; // 256KB
 
@@ -307,153 +1568,1045 @@ impl TFTPServer {
         &self,
         data: &[u8],
         addr: SocketAddr,
+        local: SocketAddr,
     ) -> Result<(), Box<dyn std::error::Error>> {
         if data.len() < 2 {
             return Err("Invalid packet size".into());
         }
 
-        let opcode = u16::from_be_bytes([data[0], data[1]]);
+        let opcode = u16::from_be_bytes([data[0], data[1]]);
+
+        let result = match opcode {
+            RRQ | WRQ => self.parse_request(&data[2..]).and_then(|(filename, mode, requested)| {
+                let (filename, root) = match self.resolve_request(&filename, addr, opcode) {
+                    Ok(resolved) => resolved,
+                    // Refusals are logged in detail where they are made, so
+                    // all that is left is to tell the client
+                    Err(e) => {
+                        self.send_error(addr, local, e.code(), &e.peer_message())?;
+                        return Ok(());
+                    }
+                };
+                if opcode == RRQ {
+                    self.handle_read_request(&root, filename, mode, requested, addr, local)
+                } else {
+                    self.handle_write_request(&root, filename, mode, requested, addr, local)
+                }
+            }),
+            _ => Err(TransferError::Protocol(
+                ERROR_ILLEGAL_OPERATION,
+                "Illegal TFTP operation".to_string(),
+            )),
+        };
+
+        // Failures that reach us here happened before a transfer socket
+        // existed, so the peer hears about them from a fresh one
+        if let Err(e) = &result
+            && e.notifies_peer()
+        {
+            self.send_error(addr, local, e.code(), &e.peer_message())?;
+        }
+        Ok(result?)
+    }
+
+    /// Applies the map file, filename checks and ACL to a request, and picks
+    /// the directory to serve it from
+    fn resolve_request(&self, filename: &str, addr: SocketAddr, opcode: u16) -> Result<(String, PathBuf), TransferError> {
+        let filename = self.map_filename(filename, addr.ip())?;
+        let filename = self.normalize_filename(&filename, addr.ip())?;
+        self.check_acl(&filename, addr, opcode)?;
+        let root = self.client_root(addr.ip())?;
+        Ok((filename, root))
+    }
+
+    /// Runs a requested filename through the map file. A `reject` rule
+    /// refuses the request with an access violation.
+    fn map_filename(&self, filename: &str, client: IpAddr) -> Result<String, TransferError> {
+        let Some(rules) = &self.map else {
+            return Ok(filename.to_string());
+        };
+
+        match map_filename(rules, filename, client) {
+            Ok(mapped) => {
+                if mapped != filename {
+                    println!("[INFO] {}Mapped {} to {} for {}", self.tag(), filename, mapped, client);
+                }
+                Ok(mapped)
+            }
+            Err(rule) => {
+                println!(
+                    "[INFO] {}Request for {} from {} rejected ({})",
+                    self.tag(),
+                    filename, client, rule.source
+                );
+                Err(TransferError::Protocol(ERROR_ACCESS_VIOLATION, "Access violation".to_string()))
+            }
+        }
+    }
+
+    /// The directory a client's requests are served from: that of the first
+    /// `--client-root` matching it, or the served directory when none does.
+    /// A client whose rule names a directory that is missing, or a `{mac}`
+    /// directory when its MAC address is unknown, is refused rather than
+    /// served from somewhere else.
+    fn client_root(&self, client: IpAddr) -> Result<PathBuf, TransferError> {
+        // Only read the ARP table if a rule needs the client's MAC address
+        let mut mac = None;
+        let mut client_mac = || *mac.get_or_insert_with(|| arp_lookup(client));
+
+        for root in self.client_roots.iter() {
+            let matches = match root.clients {
+                ClientMatch::Network(network) => network.contains(client),
+                ClientMatch::Mac(address) => client_mac() == Some(address),
+            };
+            if !matches {
+                continue;
+            }
+
+            let directory = if root.directory.contains("{mac}") {
+                let Some(address) = client_mac() else {
+                    println!("[INFO] {}No MAC address known for {}, needed by {}", self.tag(), client, root.directory);
+                    return Err(TransferError::Protocol(ERROR_ACCESS_VIOLATION, "Access violation".to_string()));
+                };
+                PathBuf::from(root.directory.replace("{mac}", &format_mac(address)))
+            } else {
+                PathBuf::from(&root.directory)
+            };
+            if !directory.is_dir() {
+                println!("[ERROR] {}Root directory {} for {} not found", self.tag(), directory.display(), client);
+                return Err(TransferError::Protocol(ERROR_FILE_NOT_FOUND, "File not found".to_string()));
+            }
+            return Ok(directory);
+        }
+        Ok(self.directory.clone())
+    }
+
+    /// Turns a requested filename into a plain relative path, refusing names
+    /// that climb out of the served directory and, unless they are to be
+    /// stripped, absolute ones.
+    fn normalize_filename(&self, filename: &str, client: IpAddr) -> Result<String, TransferError> {
+        normalize_path(filename, self.absolute_paths).ok_or_else(|| {
+            println!("[INFO] {}Access violation attempt: {} from {}", self.tag(), filename, client);
+            TransferError::Protocol(ERROR_ACCESS_VIOLATION, "Access violation".to_string())
+        })
+    }
+
+    /// Applies the access control list to a request. The first rule that
+    /// matches the client, request type and file decides; requests no rule
+    /// matches are denied.
+    fn check_acl(&self, filename: &str, addr: SocketAddr, opcode: u16) -> Result<(), TransferError> {
+        let Some(acl) = &self.acl else {
+            return Ok(());
+        };
+
+        let rule = acl.iter().find(|rule| rule.matches(addr.ip(), opcode, filename));
+        if rule.is_some_and(|rule| rule.allow) {
+            return Ok(());
+        }
+
+        println!(
+            "[INFO] {}Access denied: {} {} by {} ({})",
+            self.tag(),
+            if opcode == RRQ { "read of" } else { "write to" },
+            filename,
+            addr.ip(),
+            rule.map_or("no matching rule", |rule| &rule.source)
+        );
+        Err(TransferError::Protocol(ERROR_ACCESS_VIOLATION, "Access violation".to_string()))
+    }
+
+    /// Tells the peer why a transfer is being abandoned (unless the peer
+    /// ended it) and logs the failure
+    fn abandon_transfer(
+        &self,
+        socket: &Transport,
+        addr: SocketAddr,
+        filename: &str,
+        error: &TransferError,
+        stats: &TransferStats,
+    ) {
+        if error.notifies_peer() {
+            let _ = socket.send_to(&error_packet(error.code(), &error.peer_message()), addr);
+        }
+        eprintln!();
+        println!("[ERROR] {}{}: {}", self.tag(), filename, error);
+        stats.report(&self.tag(), filename);
+    }
+
+    /// Records a new session in `active_transfers`. Returns `None` for a
+    /// retransmitted request whose session is still live, which must not
+    /// start a second transfer from another TID. A request for anything else
+    /// from the same client address means the client has moved on, so its
+    /// older sessions there are cancelled.
+    fn register_transfer(&self, addr: SocketAddr, filename: &str, opcode: u16) -> Option<TransferGuard> {
+        let key = (addr, filename.to_string(), opcode);
+        let mut transfers = self.active_transfers.lock().unwrap();
+        if transfers.contains_key(&key) {
+            return None;
+        }
 
-        match opcode {
-            RRQ => self.handle_read_request(data, addr),
-            WRQ => self.handle_write_request(data, addr),
-            _ => {
-                self.send_error(addr, ERROR_ILLEGAL_OPERATION, "Illegal TFTP operation")?;
-                Ok(())
+        for ((client, stale, _), cancelled) in transfers.iter() {
+            if *client == addr && !cancelled.swap(true, Ordering::Relaxed) {
+                println!("[INFO] {}Cancelling stale transfer of {} for {}", self.tag(), stale, addr);
             }
         }
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        transfers.insert(key.clone(), Arc::clone(&cancelled));
+        Some(TransferGuard {
+            transfers: Arc::clone(&self.active_transfers),
+            key,
+            cancelled,
+        })
     }
 
     fn handle_read_request(
         &self,
-        data: &[u8],
+        root: &Path,
+        filename: String,
+        mode: String,
+        requested: RequestOptions,
         addr: SocketAddr,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let (filename, _mode) = self.parse_request(&data[2..])?;
-        let filepath = self.directory.join(&filename);
+        local: SocketAddr,
+    ) -> Result<(), TransferError> {
+        let Some(guard) = self.register_transfer(addr, &filename, RRQ) else {
+            println!("[INFO] {}Ignoring duplicate read request for {} from {}", self.tag(), filename, addr);
+            return Ok(());
+        };
 
-        if !filepath.starts_with(&self.directory) {
-            println!(
-                "[INFO] Access violation attempt: {} from {}",
-                filename,
-                addr.ip()
-            );
-            self.send_error(addr, ERROR_ACCESS_VIOLATION, "Access violation")?;
+        if self.access == Access::WriteOnly {
+            println!("[INFO] {}Read refused (write-only server): {} from {}", self.tag(), filename, addr.ip());
+            self.send_error(addr, local, ERROR_ACCESS_VIOLATION, "Server is write-only")?;
             return Ok(());
         }
+        let Some(mode) = self.check_mode(&mode, &filename, addr, local)? else {
+            return Ok(());
+        };
+        let filepath = root.join(&filename);
+
+        // Non-blocking, so a FIFO planted in the tree cannot stall us
+        let file = match open_beneath(root, Path::new(&filename), libc::O_RDONLY | libc::O_NONBLOCK) {
+            Ok(file) if file.metadata().is_ok_and(|metadata| metadata.is_file()) => file,
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                println!(
+                    "[INFO] {}Access violation attempt: {} from {} (resolves outside the served directory)",
+                    self.tag(),
+                    filename,
+                    addr.ip()
+                );
+                self.send_error(addr, local, ERROR_ACCESS_VIOLATION, "Access violation")?;
+                return Ok(());
+            }
+            Err(e) if !matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory) => {
+                return Err(e.into());
+            }
+            _ => {
+                println!(
+                    "[ERROR] {}File not found: {} (requested by {})",
+                    self.tag(),
+                    filename,
+                    addr.ip()
+                );
+                self.send_error(addr, local, ERROR_FILE_NOT_FOUND, "File not found")?;
+                return Ok(());
+            }
+        };
+
+        // Netascii grows on the wire, so size and progress follow the encoding
+        let file_size = match mode {
+            TransferMode::Octet => file.metadata()?.len(),
+            TransferMode::Netascii => netascii_size(&file)?,
+        };
+        let mut options = match self.negotiate_options(&requested, Some(file_size)) {
+            Ok(options) => options,
+            Err(msg) => {
+                println!(
+                    "[ERROR] {}Option negotiation failed for {}: {} (from {})",
+                    self.tag(),
+                    filename,
+                    msg,
+                    addr.ip()
+                );
+                self.send_error(addr, local, ERROR_OPTION_NEGOTIATION, &msg)?;
+                return Ok(());
+            }
+        };
 
-        if !filepath.exists() || !filepath.is_file() {
+        options.mode = mode;
+        options.cancelled = Arc::clone(&guard.cancelled);
+
+        let blocks_needed = file_size / options.blksize as u64 + 1;
+        if options.rollover.wire_block(blocks_needed).is_none() {
             println!(
-                "[ERROR] File not found: {} (requested by {})",
+                "[ERROR] {}{} needs {} blocks, more than block numbers allow without rollover (requested by {})",
+                self.tag(),
                 filename,
+                blocks_needed,
                 addr.ip()
             );
-            self.send_error(addr, ERROR_FILE_NOT_FOUND, "File not found")?;
+            self.send_error(addr, local, ERROR_NOT_DEFINED, "File too large for this block size")?;
             return Ok(());
         }
 
-        let file_size = std::fs::metadata(&filepath)?.len();
+        if options.multicast {
+            // Multicast clients are lock-stepped by the master and cannot
+            // follow a block number rollover
+            options.windowsize = 1;
+            options.acknowledged.retain(|(name, _)| name != "windowsize");
+
+            // RFC 2090 groups are IPv4, IPv6 clients are served by unicast
+            if blocks_needed > u16::MAX as u64
+                || addr.is_ipv6()
+                || !self.join_multicast(&filepath, &file, addr, local, &filename, &options)? {
+                options.multicast = false;
+                options.acknowledged.retain(|(name, _)| name != "multicast");
+            } else {
+                return Ok(());
+            }
+        }
+
+        let transport = self.open_transport(addr, local)?;
+
         println!(
-            "[INFO] Upload started: {} ({}) to {}:{}",
+            "[INFO] {}Upload started: {} ({}) to {}:{}",
+            self.tag(),
             filename,
             format_size(file_size),
             addr.ip(),
             addr.port()
         );
 
-        let transfer_socket = UdpSocket::bind("0.0.0.0:0")?;
-        self.optimize_socket(&transfer_socket)?;
-
-        self.send_file(&filepath, addr, &transfer_socket, &filename, file_size)?;
+        let mut stats = TransferStats::default();
+        if let Err(e) = self.send_file(file, addr, &transport, &filename, file_size, &options, &mut stats) {
+            self.abandon_transfer(&transport, addr, &filename, &e, &stats);
+        }
         Ok(())
     }
 
     fn handle_write_request(
         &self,
-        data: &[u8],
+        root: &Path,
+        filename: String,
+        mode: String,
+        requested: RequestOptions,
         addr: SocketAddr,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let (filename, _mode) = self.parse_request(&data[2..])?;
-        let filepath = self.directory.join(&filename);
+        local: SocketAddr,
+    ) -> Result<(), TransferError> {
+        let Some(guard) = self.register_transfer(addr, &filename, WRQ) else {
+            println!("[INFO] {}Ignoring duplicate write request for {} from {}", self.tag(), filename, addr);
+            return Ok(());
+        };
+
+        if self.access == Access::ReadOnly {
+            println!("[INFO] {}Write refused (read-only server): {} from {}", self.tag(), filename, addr.ip());
+            self.send_error(addr, local, ERROR_ACCESS_VIOLATION, "Server is read-only")?;
+            return Ok(());
+        }
+        let Some(mode) = self.check_mode(&mode, &filename, addr, local)? else {
+            return Ok(());
+        };
+        let target = match UploadTarget::open(root, &filename) {
+            Ok(target) => target,
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                println!(
+                    "[INFO] {}Access violation attempt: {} from {} (resolves outside the served directory)",
+                    self.tag(),
+                    filename,
+                    addr.ip()
+                );
+                self.send_error(addr, local, ERROR_ACCESS_VIOLATION, "Access violation")?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut options = match self.negotiate_options(&requested, None) {
+            Ok(options) => options,
+            Err(msg) => {
+                println!(
+                    "[ERROR] {}Option negotiation failed for {}: {} (from {})",
+                    self.tag(),
+                    filename,
+                    msg,
+                    addr.ip()
+                );
+                self.send_error(addr, local, ERROR_OPTION_NEGOTIATION, &msg)?;
+                return Ok(());
+            }
+        };
+
+        options.mode = mode;
+        options.cancelled = Arc::clone(&guard.cancelled);
+
+        // A spent quota, or a declared size, lets us turn away uploads that
+        // can never fit
+        let mut charge = UploadCharge::new(self, root, addr.ip());
+        let limit = charge.remaining();
+        let refusal = if let Some(limit) = limit.filter(|limit| limit.bytes == 0) {
+            Some(limit.reason)
+        } else if let Some(size) = options.tsize {
+            if let Some(limit) = limit.filter(|limit| size > limit.bytes) {
+                Some(limit.reason)
+            } else if available_space(&target.directory).is_some_and(|free| size > free) {
+                Some("Not enough free disk space")
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        if let Some(msg) = refusal {
+            println!(
+                "[ERROR] {}Upload refused: {} ({}) from {}: {}",
+                self.tag(),
+                filename,
+                options.tsize.map_or_else(|| "size not given".to_string(), format_size),
+                addr.ip(),
+                msg
+            );
+            self.send_error(addr, local, ERROR_DISK_FULL, msg)?;
+            return Ok(());
+        }
 
-        if !filepath.starts_with(&self.directory) {
-            self.send_error(addr, ERROR_ACCESS_VIOLATION, "Access violation")?;
+        if target.exists() && self.no_overwrite {
+            println!(
+                "[INFO] {}Write refused, file exists: {} (from {})",
+                self.tag(),
+                filename,
+                addr.ip()
+            );
+            self.send_error(addr, local, ERROR_FILE_EXISTS, "File already exists")?;
             return Ok(());
         }
 
-        if filepath.exists() {
+        let transport = self.open_transport(addr, local)?;
+
+        if target.exists() {
             println!(
-                "[INFO] File exists, overwriting: {} (from {})",
+                "[INFO] {}File exists, overwriting: {} (from {})",
+                self.tag(),
                 filename,
                 addr.ip()
             );
         } else {
             println!(
-                "[INFO] Download started: {} from {}:{}",
+                "[INFO] {}Download started: {} from {}:{}",
+                self.tag(),
                 filename,
                 addr.ip(),
                 addr.port()
             );
         }
 
-        let transfer_socket = UdpSocket::bind("0.0.0.0:0")?;
-        self.optimize_socket(&transfer_socket)?;
+        let mut stats = TransferStats::default();
+        if let Err(e) = self.receive_file(&target, addr, &transport, &filename, &options, &mut charge, &mut stats) {
+            self.abandon_transfer(&transport, addr, &filename, &e, &stats);
+        }
+        Ok(())
+    }
+
+    /// Resolves the mode string of a request, answering unsupported modes
+    /// (such as "mail") with an error. Returns `None` when the request was
+    /// refused.
+    fn check_mode(
+        &self,
+        mode: &str,
+        filename: &str,
+        addr: SocketAddr,
+        local: SocketAddr,
+    ) -> std::io::Result<Option<TransferMode>> {
+        let parsed = TransferMode::parse(mode);
+        if parsed.is_none() {
+            println!(
+                "[ERROR] {}Unsupported transfer mode '{}' for {} (from {})",
+                self.tag(),
+                mode,
+                filename,
+                addr.ip()
+            );
+            self.send_error(addr, local, ERROR_ILLEGAL_OPERATION, "Unsupported transfer mode")?;
+        }
+        Ok(parsed)
+    }
+
+    /// Adds a client to the multicast session for its file, starting one and
+    /// running it on this thread when none exists. Returns `false` when no
+    /// group is free and the client should be served by plain unicast.
+    fn join_multicast(
+        &self,
+        filepath: &Path,
+        file: &File,
+        addr: SocketAddr,
+        local: SocketAddr,
+        filename: &str,
+        options: &TransferOptions,
+    ) -> Result<bool, TransferError> {
+        // DATA goes out of the interface the request came in on
+        let interface = match local.ip().to_canonical() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let key = (filepath.to_path_buf(), options.mode, options.blksize, interface, self.name.clone());
+        let client = MulticastClient {
+            addr,
+            acknowledged: options.acknowledged.clone(),
+        };
+
+        let group = {
+            let mut sessions = self.multicast_sessions.lock().unwrap();
+            if let Some(session) = sessions.get_mut(&key) {
+                session.joining.push(client);
+                return Ok(true);
+            }
+
+            let Some(base) = self.multicast_base else {
+                return Ok(false);
+            };
+            // One group address per session, counting up from the base but
+            // not past the end of 224.0.0.0/4
+            let Some(group) = (0..256u32)
+                .map(|i| Ipv4Addr::from(u32::from(*base.ip()) + i))
+                .take_while(|ip| ip.is_multicast())
+                .map(|ip| SocketAddrV4::new(ip, base.port()))
+                .find(|group| sessions.values().all(|session| session.group != *group))
+            else {
+                return Ok(false);
+            };
+
+            sessions.insert(
+                key.clone(),
+                MulticastSession {
+                    group,
+                    joining: vec![client],
+                },
+            );
+            group
+        };
+
+        println!(
+            "[INFO] {}Multicast session started: {} on {} (first client {})",
+            self.tag(),
+            filename,
+            group,
+            addr.ip()
+        );
+
+        let mut stats = TransferStats::default();
+        let result = self.run_multicast_session(&key, file, group, filename, options, &mut stats);
+
+        // Normally the loop removes the session itself once it runs dry.
+        // Clients still waiting to join must hear about a failure too.
+        let stranded = self.multicast_sessions.lock().unwrap().remove(&key);
+        if let Err(e) = result {
+            println!("[ERROR] {}Multicast session for {} failed: {}", self.tag(), filename, e);
+            stats.report(&self.tag(), filename);
+            for client in stranded.map(|session| session.joining).unwrap_or_default() {
+                self.send_error(client.addr, local, e.code(), &e.peer_message())?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Drives a multicast session: the file is read once, DATA goes to the
+    /// group, and only the current master client acknowledges. When the
+    /// master has every block the next client is promoted and resumes from
+    /// the first block it is missing.
+    fn run_multicast_session(
+        &self,
+        key: &MulticastKey,
+        file: &File,
+        group: SocketAddrV4,
+        filename: &str,
+        options: &TransferOptions,
+        stats: &mut TransferStats,
+    ) -> Result<(), TransferError> {
+        let mut contents = Vec::new();
+        match options.mode {
+            TransferMode::Octet => BufReader::new(file).read_to_end(&mut contents)?,
+            TransferMode::Netascii => NetasciiReader::new(file).read_to_end(&mut contents)?,
+        };
+        let total_blocks = (contents.len() / options.blksize + 1) as u16;
+        let data_packet = |block: u16| {
+            let start = std::cmp::min((block as usize - 1) * options.blksize, contents.len());
+            let end = std::cmp::min(start + options.blksize, contents.len());
+            let mut packet = Vec::with_capacity(4 + end - start);
+            packet.extend_from_slice(&DATA.to_be_bytes());
+            packet.extend_from_slice(&block.to_be_bytes());
+            packet.extend_from_slice(&contents[start..end]);
+            packet
+        };
+
+        let interface = key.3;
+        let socket = self.bind_reply_socket(SocketAddr::from((interface, 0)))?;
+        self.optimize_socket(&socket)?;
+        if !interface.is_unspecified() {
+            set_multicast_interface(&socket, interface)?;
+        }
+        // Short reads keep joining clients from waiting a full timeout
+        socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+
+        let mut clients: VecDeque<MulticastClient> = VecDeque::new();
+        let mut master: Option<SocketAddr> = None;
+        // The packet the master owes us an ACK for, where it went, and the
+        // lowest block number that ACK may carry (0 after an OACK)
+        let mut outstanding: Option<(Vec<u8>, SocketAddr, u16)> = None;
+        let mut deadline = Instant::now();
+        let mut retries = 0;
+        let mut served = 0;
+        let mut buffer = [0; 1024];
+
+        // Run the session in a closure so every member can be told when it
+        // fails part-way
+        let mut run = || -> Result<(), TransferError> {
+            loop {
+                let joined = {
+                    let mut sessions = self.multicast_sessions.lock().unwrap();
+                    let Some(session) = sessions.get_mut(key) else {
+                        break;
+                    };
+                    if clients.is_empty() && session.joining.is_empty() {
+                        sessions.remove(key);
+                        break;
+                    }
+                    std::mem::take(&mut session.joining)
+                };
+
+                let mut new_clients = Vec::new();
+                for client in joined {
+                    match clients.iter().find(|member| member.addr == client.addr) {
+                        // A retransmitted RRQ, repeat the OACK it is waiting for
+                        Some(member) => {
+                            let oack = member.oack_packet(group, master == Some(member.addr));
+                            socket.send_to(&oack, client.addr)?;
+                        }
+                        None => {
+                            println!("[INFO] {}{} joined multicast session for {}", self.tag(), client.addr.ip(), filename);
+                            new_clients.push(client.addr);
+                            clients.push_back(client);
+                        }
+                    }
+                }
+
+                if master.is_none()
+                    && let Some(client) = clients.front()
+                {
+                    let oack = client.oack_packet(group, true);
+                    socket.send_to(&oack, client.addr)?;
+                    master = Some(client.addr);
+                    outstanding = Some((oack, client.addr, 0));
+                    retries = 0;
+                    deadline = Instant::now() + calculate_timeout(options.timeout, 0);
+                }
+
+                for addr in new_clients.into_iter().filter(|&addr| master != Some(addr)) {
+                    if let Some(client) = clients.iter().find(|client| client.addr == addr) {
+                        socket.send_to(&client.oack_packet(group, false), addr)?;
+                    }
+                }
+
+                match socket.recv_from(&mut buffer) {
+                    Ok((size, recv_addr)) => {
+                        if !clients.iter().any(|client| client.addr == recv_addr) {
+                            stats.unknown_tids += 1;
+                            println!(
+                                "[INFO] {}Packet from unknown transfer ID {} (multicast session for {}), sent error 5",
+                                self.tag(),
+                                recv_addr, filename
+                            );
+                            let packet = error_packet(ERROR_UNKNOWN_TID, "Unknown transfer ID");
+                            socket.send_to(&packet, recv_addr)?;
+                            continue;
+                        }
+                        if size < 4 {
+                            continue;
+                        }
+
+                        let opcode = u16::from_be_bytes([buffer[0], buffer[1]]);
+                        let block = u16::from_be_bytes([buffer[2], buffer[3]]);
+
+                        if opcode == ERROR {
+                            println!("[INFO] {}{} left multicast session for {}", self.tag(), recv_addr.ip(), filename);
+                            clients.retain(|client| client.addr != recv_addr);
+                            if master == Some(recv_addr) {
+                                master = None;
+                                outstanding = None;
+                            }
+                        } else if opcode == ACK
+                            && master == Some(recv_addr)
+                            && outstanding.as_ref().is_some_and(|&(_, _, awaited)| block < awaited)
+                        {
+                            // A duplicate of an ACK already acted on. Only a
+                            // timeout resends, or each copy would double the
+                            // traffic (Sorcerer's Apprentice).
+                            stats.duplicate_acks += 1;
+                        } else if opcode == ACK && master == Some(recv_addr) {
+                            if block >= total_blocks {
+                                // The master has the whole file
+                                served += 1;
+                                clients.retain(|client| client.addr != recv_addr);
+                                master = None;
+                                outstanding = None;
+                            } else {
+                                let packet = data_packet(block + 1);
+                                socket.send_to(&packet, group)?;
+                                outstanding = Some((packet, SocketAddr::V4(group), block + 1));
+                                retries = 0;
+                                deadline = Instant::now() + calculate_timeout(options.timeout, 0);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        if e.kind() != std::io::ErrorKind::TimedOut
+                            && e.kind() != std::io::ErrorKind::WouldBlock
+                        {
+                            return Err(e.into());
+                        }
+
+                        if let Some((packet, dest, _)) = &outstanding
+                            && Instant::now() >= deadline
+                        {
+                            retries += 1;
+                            if let Some(stalled) = master
+                                && retries >= MAX_RETRIES
+                            {
+                                println!(
+                                    "[ERROR] {}Multicast master {} stopped responding for {}",
+                                    self.tag(),
+                                    stalled.ip(),
+                                    filename
+                                );
+                                let timeout = TransferError::Timeout;
+                                socket.send_to(&error_packet(timeout.code(), &timeout.peer_message()), stalled)?;
+                                clients.retain(|client| client.addr != stalled);
+                                master = None;
+                                outstanding = None;
+                            } else {
+                                socket.send_to(packet, *dest)?;
+                                deadline = Instant::now() + calculate_timeout(options.timeout, retries);
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(())
+        };
+
+        if let Err(e) = run() {
+            for client in &clients {
+                let _ = socket.send_to(&error_packet(e.code(), &e.peer_message()), client.addr);
+            }
+            return Err(e);
+        }
 
-        self.receive_file(&filepath, addr, &transfer_socket, &filename)?;
+        println!(
+            "[INFO] {}Multicast session finished: {} ({}) sent to {} client(s)",
+            self.tag(),
+            filename,
+            format_size(contents.len() as u64),
+            served
+        );
+        stats.report(&self.tag(), filename);
         Ok(())
     }
 
+    /// Sends the OACK for a read request and waits for the client to confirm
+    /// it with ACK 0
+    fn send_oack(
+        &self,
+        oack: &[u8],
+        addr: SocketAddr,
+        socket: &Transport,
+        timeout: Duration,
+        stats: &mut TransferStats,
+    ) -> Result<(), TransferError> {
+        let mut buffer = [0; 1024];
+        let mut retries = 0;
+        let mut resend = true;
+        let mut deadline = Instant::now();
+
+        // Only a timeout resends the OACK, so stray packets cannot make us
+        // send one for each of them
+        while retries < MAX_RETRIES {
+            if resend {
+                socket.send_to(oack, addr)?;
+                deadline = Instant::now() + calculate_timeout(timeout, retries);
+                resend = false;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.recv_from_peer(socket, &mut buffer, addr, remaining, stats) {
+                Ok(size) => {
+                    if size < 4 {
+                        stats.ignored_packets += 1;
+                        continue;
+                    }
+
+                    let opcode = u16::from_be_bytes([buffer[0], buffer[1]]);
+                    let block = u16::from_be_bytes([buffer[2], buffer[3]]);
+
+                    if opcode == ACK && block == 0 {
+                        return Ok(());
+                    } else if opcode == ERROR {
+                        // Typically error 8, the client declining our options
+                        return Err(peer_error(&buffer[..size]));
+                    } else if opcode == ACK {
+                        stats.duplicate_acks += 1;
+                    } else {
+                        stats.ignored_packets += 1;
+                    }
+                }
+                Err(e) => {
+                    if e.kind() == std::io::ErrorKind::TimedOut
+                        || e.kind() == std::io::ErrorKind::WouldBlock
+                    {
+                        retries += 1;
+                        resend = true;
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Err(TransferError::Timeout)
+    }
+
+    /// Waits up to `timeout` for a packet from `peer`. Packets from anyone
+    /// else are answered with error 5 (Unknown transfer ID) without
+    /// disturbing the session, as RFC 1350 requires.
+    fn recv_from_peer(
+        &self,
+        socket: &Transport,
+        buffer: &mut [u8],
+        peer: SocketAddr,
+        timeout: Duration,
+        stats: &mut TransferStats,
+    ) -> std::io::Result<usize> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            let (size, recv_addr) = socket.recv_from(buffer, remaining)?;
+            if recv_addr == peer {
+                return Ok(size);
+            }
+
+            stats.unknown_tids += 1;
+            eprintln!();
+            println!(
+                "[INFO] {}Packet from unknown transfer ID {} (session with {}), sent error 5",
+                self.tag(),
+                recv_addr, peer
+            );
+            socket.send_to(&error_packet(ERROR_UNKNOWN_TID, "Unknown transfer ID"), recv_addr)?;
+        }
+    }
+
+    /// Applies the options requested by the client, returning the message for
+    /// an error 8 when negotiation fails. Options the server does not know are
+    /// left out of the OACK, as RFC 2347 requires.
+    ///
+    /// `file_size` is the size of the file being read, or `None` for a write
+    /// request, where the client tells us the size instead.
+    fn negotiate_options(
+        &self,
+        requested: &[(String, String)],
+        file_size: Option<u64>,
+    ) -> Result<TransferOptions, String> {
+        let mut options = TransferOptions {
+            rollover: self.rollover,
+            ..TransferOptions::default()
+        };
+        let mut seen = Vec::new();
+
+        for (name, value) in requested {
+            if seen.contains(&name) {
+                return Err(format!("Duplicate option: {}", name));
+            }
+            seen.push(name);
+
+            match name.as_str() {
+                "blksize" => {
+                    let blksize = value
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&size| size >= MIN_BLKSIZE)
+                        .ok_or_else(|| format!("Invalid blksize: {}", value))?;
+                    // Larger requests are answered with the largest size we allow
+                    options.blksize = blksize.min(self.max_blksize);
+                    options
+                        .acknowledged
+                        .push((name.clone(), options.blksize.to_string()));
+                }
+                "windowsize" => {
+                    let windowsize = value
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|&size| size >= 1)
+                        .ok_or_else(|| format!("Invalid windowsize: {}", value))?;
+                    options.windowsize = (windowsize as usize).min(self.max_windowsize);
+                    options
+                        .acknowledged
+                        .push((name.clone(), options.windowsize.to_string()));
+                }
+                "tsize" => {
+                    let declared = value
+                        .parse::<u64>()
+                        .map_err(|_| format!("Invalid tsize: {}", value))?;
+                    // Readers send 0 and get the real size back
+                    let tsize = file_size.unwrap_or(declared);
+                    options.tsize = Some(tsize);
+                    options.acknowledged.push((name.clone(), tsize.to_string()));
+                }
+                "timeout" => {
+                    let seconds = value
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|seconds| (1..=255).contains(seconds))
+                        .ok_or_else(|| format!("Invalid timeout: {}", value))?;
+                    options.timeout = Duration::from_secs(seconds);
+                    options.acknowledged.push((name.clone(), seconds.to_string()));
+                }
+                "utimeout" => {
+                    let millis = value
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|millis| (MIN_UTIMEOUT_MS..=MAX_UTIMEOUT_MS).contains(millis))
+                        .ok_or_else(|| format!("Invalid utimeout: {}", value))?;
+                    options.timeout = Duration::from_millis(millis);
+                    options.acknowledged.push((name.clone(), millis.to_string()));
+                }
+                "rollover" => {
+                    options.rollover = match value.as_str() {
+                        "0" => Rollover::Zero,
+                        "1" => Rollover::One,
+                        _ => return Err(format!("Invalid rollover: {}", value)),
+                    };
+                    options.acknowledged.push((name.clone(), value.clone()));
+                }
+                // Only offered for reads, and only when a group range is set
+                "multicast" if self.multicast_base.is_some() && file_size.is_some() => {
+                    options.multicast = true;
+                    options.acknowledged.push((name.clone(), String::new()));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(options)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn send_file(
         &self,
-        filepath: &Path,
+        file: File,
         addr: SocketAddr,
-        socket: &UdpSocket,
+        socket: &Transport,
         filename: &str,
         file_size: u64,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut file = File::open(filepath)?;
-        let mut buffer = [0; PACKET_SIZE];
-        let mut block_num: u16 = 1;
+        options: &TransferOptions,
+        stats: &mut TransferStats,
+    ) -> Result<(), TransferError> {
+        let mut reader: Box<dyn Read> = match options.mode {
+            TransferMode::Octet => Box::new(file),
+            TransferMode::Netascii => Box::new(NetasciiReader::new(file)),
+        };
+        // DATA packets sent but not yet acknowledged, oldest first
+        let mut window: VecDeque<Vec<u8>> = VecDeque::with_capacity(options.windowsize);
+        // Positions in the transfer; Rollover maps them to wire numbers
+        let mut next_block: u64 = 1;
+        let mut last_acked: u64 = 0;
+        let mut read_last_block = false;
         let mut bytes_sent = 0u64;
         let mut progress_bar = ProgressBar::new(filename.to_string());
 
         let start_time = Instant::now();
         let mut last_update = Instant::now();
         let mut consecutive_timeouts = 0;
+        let mut ack_buffer = [0; 1024];
+
+        if let Some(oack) = options.oack_packet() {
+            self.send_oack(&oack, addr, socket, options.timeout, stats)?;
+        }
 
         loop {
-            let bytes_read = file.read(&mut buffer)?;
+            while !read_last_block && window.len() < options.windowsize {
+                let Some(wire_block) = options.rollover.wire_block(next_block) else {
+                    // The file grew since we checked its size
+                    return Err(TransferError::Protocol(
+                        ERROR_NOT_DEFINED,
+                        "File too large for this block size".to_string(),
+                    ));
+                };
 
-            let mut packet = Vec::with_capacity(4 + bytes_read);
-            packet.extend_from_slice(&DATA.to_be_bytes());
-            packet.extend_from_slice(&block_num.to_be_bytes());
-            packet.extend_from_slice(&buffer[..bytes_read]);
+                let mut packet = vec![0; 4 + options.blksize];
+                packet[..2].copy_from_slice(&DATA.to_be_bytes());
+                packet[2..4].copy_from_slice(&wire_block.to_be_bytes());
+                let bytes_read = read_block(&mut reader, &mut packet[4..])?;
+                packet.truncate(4 + bytes_read);
 
-            let mut retries = 0;
-            let mut acked = false;
-            let mut ack_buffer = [0; 1024];
+                read_last_block = bytes_read < options.blksize;
+                next_block += 1;
+                window.push_back(packet);
+            }
+
+            if window.is_empty() {
+                break;
+            }
 
-            while retries < MAX_RETRIES && !acked {
-                socket.send_to(&packet, addr)?;
+            let mut retries = 0;
+            let mut acked = 0;
+            let mut resend = true;
+            let mut deadline = Instant::now();
+
+            // Send the whole window, then wait for a cumulative ACK. On a
+            // timeout we roll back and resend from the last acknowledged block.
+            while retries < MAX_RETRIES && acked == 0 {
+                options.check_cancelled()?;
+
+                if resend {
+                    for packet in &window {
+                        socket.send_to(packet, addr)?;
+                    }
+                    deadline = Instant::now() + calculate_timeout(options.timeout, retries);
+                    resend = false;
+                }
 
-                let timeout = calculate_timeout(retries);
-                socket.set_read_timeout(Some(timeout))?;
+                let timeout = deadline.saturating_duration_since(Instant::now());
 
-                match socket.recv_from(&mut ack_buffer) {
-                    Ok((ack_size, recv_addr)) => {
-                        if recv_addr == addr && ack_size >= 4 {
+                match self.recv_from_peer(socket, &mut ack_buffer, addr, timeout, stats) {
+                    Ok(ack_size) => {
+                        if ack_size >= 4 {
                             let ack_opcode = u16::from_be_bytes([ack_buffer[0], ack_buffer[1]]);
                             let ack_block = u16::from_be_bytes([ack_buffer[2], ack_buffer[3]]);
-                            
-                            if ack_opcode == ACK && ack_block == block_num {
-                                acked = true;
+                            // Match the ACK against the blocks it can refer to,
+                            // which stays unambiguous across the wrap point
+                            let offset = (0..=window.len() as u64).find(|&offset| {
+                                options.rollover.wire_block(last_acked + offset) == Some(ack_block)
+                            });
+
+                            if ack_opcode == ACK
+                                && let Some(offset) = offset.filter(|&offset| offset >= 1)
+                            {
+                                acked = offset as usize;
                                 consecutive_timeouts = 0; // Reset timeout counter
-                            } else if ack_opcode == ACK && ack_block == block_num.wrapping_sub(1) {
-                                // Duplicate ACK, just continue
-                                continue;
+                            } else if ack_opcode == ERROR {
+                                return Err(peer_error(&ack_buffer[..ack_size]));
+                            } else if ack_opcode == ACK {
+                                // Duplicate, stale or out-of-window ACK: keep
+                                // waiting on the current timer. Resending here
+                                // is the Sorcerer's Apprentice bug, so only a
+                                // timeout resends.
+                                stats.duplicate_acks += 1;
                             } else {
-                                retries += 1;
+                                stats.ignored_packets += 1;
                             }
+                        } else {
+                            stats.ignored_packets += 1;
                         }
                     }
                     Err(e) => {
@@ -461,6 +2614,7 @@ impl TFTPServer {
                            || e.kind() == std::io::ErrorKind::WouldBlock {
                             retries += 1;
                             consecutive_timeouts += 1;
+                            resend = true;
                             
                             if retries < MAX_RETRIES {
                                 progress_bar.retry_info(retries, MAX_RETRIES);
@@ -469,15 +2623,14 @@ impl TFTPServer {
                                 thread::sleep(Duration::from_millis(50 + (retries * 25) as u64));
                             }
                         } else {
-                            return Err(format!("Network error: {}", e).into());
+                            return Err(e.into());
                         }
                     }
                 }
             }
 
-            if !acked {
-                progress_bar.error(&format!("Transfer failed after {} retries - network unstable", MAX_RETRIES));
-                return Ok(());
+            if acked == 0 {
+                return Err(TransferError::Timeout);
             }
 
             // Adaptive delay based on network conditions
@@ -485,15 +2638,16 @@ impl TFTPServer {
                 thread::sleep(Duration::from_millis(100)); // Slow down on poor network
             }
 
-            bytes_sent += bytes_read as u64;
+            for packet in window.drain(..acked) {
+                bytes_sent += (packet.len() - 4) as u64;
+            }
+            last_acked += acked as u64;
+
             let now = Instant::now();
+            let finished = read_last_block && window.is_empty();
 
-            if now.duration_since(last_update).as_millis() >= 100 || bytes_read < PACKET_SIZE {
-                let progress = if file_size > 0 {
-                    ((bytes_sent * 100) / file_size) as u32
-                } else {
-                    100
-                };
+            if now.duration_since(last_update).as_millis() >= 100 || finished {
+                let progress = (bytes_sent * 100).checked_div(file_size).unwrap_or(100) as u32;
 
                 let elapsed = now.duration_since(start_time).as_secs_f64();
                 let speed = if elapsed > 0.0 {
@@ -505,50 +2659,53 @@ impl TFTPServer {
                 progress_bar.update(progress, bytes_sent, file_size, speed);
                 last_update = now;
             }
-
-            block_num = block_num.wrapping_add(1);
-
-            if bytes_read < PACKET_SIZE {
-                break;
-            }
         }
 
-        progress_bar.finish("Upload", bytes_sent, addr.ip());
+        progress_bar.finish(&self.tag(), "Upload", bytes_sent, addr.ip());
+        stats.report(&self.tag(), filename);
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn receive_file(
         &self,
-        filepath: &Path,
+        target: &UploadTarget,
         addr: SocketAddr,
-        socket: &UdpSocket,
+        socket: &Transport,
         filename: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let ack_packet = [0, 4, 0, 0];
-        socket.send_to(&ack_packet, addr)?;
-
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(filepath)?;
-
-        let mut expected_block: u16 = 1;
+        options: &TransferOptions,
+        charge: &mut UploadCharge<'_>,
+        stats: &mut TransferStats,
+    ) -> Result<(), TransferError> {
+        // With options in play the OACK stands in for ACK 0
+        let mut last_ack = options.oack_packet().unwrap_or_else(|| vec![0, 4, 0, 0]);
+        socket.send_to(&last_ack, addr)?;
+
+        let mut upload = PartialUpload::create(target)?;
+
+        let mut decoder = (options.mode == TransferMode::Netascii).then(NetasciiDecoder::default);
+        let mut expected_block: u64 = 1;
+        // In-order blocks received since our last ACK, and whether a gap in
+        // the current window has already been reported (RFC 7440)
+        let mut blocks_since_ack = 0;
+        let mut gap_reported = false;
         let mut bytes_received = 0u64;
-        let mut buffer = [0; 1024];
+        let mut buffer = vec![0; options.blksize + 4];
         let mut progress_bar = ProgressBar::new(filename.to_string());
         let mut consecutive_timeouts = 0;
 
         let start_time = Instant::now();
         let mut last_update = Instant::now();
         let mut last_progress = 0u32;
-
-        socket.set_read_timeout(Some(Duration::from_millis(INITIAL_TIMEOUT_MS * 2)))?;
+        let mut timeout = options.timeout * 2;
+        let mut next_space_check = 0u64;
 
         loop {
-            match socket.recv_from(&mut buffer) {
-                Ok((size, recv_addr)) => {
-                    if recv_addr != addr || size < 4 {
+            options.check_cancelled()?;
+
+            match self.recv_from_peer(socket, &mut buffer, addr, timeout, stats) {
+                Ok(size) => {
+                    if size < 4 {
                         continue;
                     }
 
@@ -557,19 +2714,54 @@ impl TFTPServer {
                     let opcode = u16::from_be_bytes([buffer[0], buffer[1]]);
                     let block_num = u16::from_be_bytes([buffer[2], buffer[3]]);
 
-                    if opcode == DATA && block_num == expected_block {
+                    if opcode == DATA && options.rollover.wire_block(expected_block) == Some(block_num) {
                         let file_data = &buffer[4..size];
-                        file.write_all(file_data)?;
-                        bytes_received += file_data.len() as u64;
 
-                        let ack_packet = [0, 4, buffer[2], buffer[3]];
-                        socket.send_to(&ack_packet, addr)?;
+                        charge.charge(file_data.len() as u64)?;
+                        let received = bytes_received + file_data.len() as u64;
+                        // Stop before the disk fills up rather than when it has
+                        if received >= next_space_check {
+                            let needed = options.tsize.unwrap_or(0).saturating_sub(received).max(options.blksize as u64);
+                            if available_space(&target.directory).is_some_and(|free| free < needed) {
+                                return Err(TransferError::Protocol(
+                                    ERROR_DISK_FULL,
+                                    "Not enough free disk space".to_string(),
+                                ));
+                            }
+                            next_space_check = received + 1024 * 1024;
+                        }
+
+                        match decoder.as_mut() {
+                            Some(decoder) => upload.file.write_all(&decoder.decode(file_data))?,
+                            None => upload.file.write_all(file_data)?,
+                        }
+                        bytes_received += file_data.len() as u64;
 
                         let now = Instant::now();
-                        let is_last_packet = file_data.len() < PACKET_SIZE;
+                        let is_last_packet = file_data.len() < options.blksize;
+
+                        if !is_last_packet && options.rollover.wire_block(expected_block + 1).is_none() {
+                            return Err(TransferError::Protocol(
+                                ERROR_NOT_DEFINED,
+                                "File too large for this block size".to_string(),
+                            ));
+                        }
+
+                        // Only the final block of each window is acknowledged.
+                        // The last block is acknowledged once the file is in
+                        // place.
+                        blocks_since_ack += 1;
+                        gap_reported = false;
+                        last_ack = vec![0, 4, buffer[2], buffer[3]];
+                        if blocks_since_ack >= options.windowsize && !is_last_packet {
+                            socket.send_to(&last_ack, addr)?;
+                            blocks_since_ack = 0;
+                        }
 
                         let progress = if is_last_packet {
                             100
+                        } else if let Some(total) = options.tsize.filter(|&total| total > 0) {
+                            std::cmp::min(bytes_received * 100 / total, 99) as u32
                         } else {
                             let mb_received = bytes_received / (1024 * 1024);
                             std::cmp::min((mb_received * 2).min(95) as u32, 95)
@@ -587,27 +2779,36 @@ impl TFTPServer {
                                 0.0
                             };
 
-                            progress_bar.update(progress, bytes_received, bytes_received, speed);
+                            let total = options.tsize.unwrap_or(bytes_received).max(bytes_received);
+                            progress_bar.update(progress, bytes_received, total, speed);
                             last_update = now;
                             last_progress = progress;
                         }
 
-                        expected_block = expected_block.wrapping_add(1);
+                        expected_block += 1;
 
                         if is_last_packet {
+                            if let Some(decoder) = &decoder {
+                                upload.file.write_all(decoder.finish())?;
+                            }
+                            upload.commit(target, !self.no_overwrite)?;
+                            charge.keep();
+                            socket.send_to(&last_ack, addr)?;
                             break;
                         }
                     } else if opcode == DATA {
-                        if block_num == expected_block.wrapping_sub(1) {
-                            let prev_block = expected_block.wrapping_sub(1);
-                            let ack_packet = [0, 4, (prev_block >> 8) as u8, prev_block as u8];
-                            socket.send_to(&ack_packet, addr)?;
+                        // A duplicate or out-of-order block: acknowledge the
+                        // last block received in order so the sender restarts
+                        // its window from there
+                        if options.windowsize == 1 || !gap_reported {
+                            let prev_block = options.rollover.wire_block(expected_block - 1).unwrap_or(0);
+                            last_ack = vec![0, 4, (prev_block >> 8) as u8, prev_block as u8];
+                            socket.send_to(&last_ack, addr)?;
+                            blocks_since_ack = 0;
+                            gap_reported = true;
                         }
                     } else if opcode == ERROR {
-                        let error_code = u16::from_be_bytes([buffer[2], buffer[3]]);
-                        let error_msg = String::from_utf8_lossy(&buffer[4..size-1]);
-                        progress_bar.error(&format!("Client error {}: {}", error_code, error_msg));
-                        return Ok(());
+                        return Err(peer_error(&buffer[..size]));
                     }
                 }
                 Err(e) => {
@@ -616,84 +2817,94 @@ impl TFTPServer {
                         consecutive_timeouts += 1;
                         
                         if consecutive_timeouts >= MAX_RETRIES {
-                            progress_bar.error("Transfer timeout - network unstable");
-                            return Ok(());
+                            return Err(TransferError::Timeout);
                         }
                         
+                        // Our last ACK (or the OACK) may have been lost
+                        socket.send_to(&last_ack, addr)?;
+
                         // Increase timeout on consecutive failures
-                        let new_timeout = calculate_timeout(consecutive_timeouts);
-                        socket.set_read_timeout(Some(new_timeout))?;
+                        timeout = calculate_timeout(options.timeout, consecutive_timeouts);
                         
                         continue;
                     }
-                    return Err(format!("Network error: {}", e).into());
+                    return Err(e.into());
                 }
             }
         }
 
-        progress_bar.finish("Download", bytes_received, addr.ip());
+        progress_bar.finish(&self.tag(), "Download", bytes_received, addr.ip());
+        stats.report(&self.tag(), filename);
         Ok(())
     }
 
-    fn parse_request(&self, data: &[u8]) -> Result<(String, String), Box<dyn std::error::Error>> {
+    /// Splits a request into filename, mode and the RFC 2347 option/value
+    /// pairs that follow them. Option names are case-insensitive, so they are
+    /// returned lowercased.
+    fn parse_request(
+        &self,
+        data: &[u8],
+    ) -> Result<(String, String, RequestOptions), TransferError> {
         let mut parts = Vec::new();
         let mut current = Vec::new();
 
         for &byte in data {
             if byte == 0 {
-                if !current.is_empty() {
-                    parts.push(String::from_utf8(current)?);
-                    current = Vec::new();
-                }
-                if parts.len() >= 2 {
-                    break;
-                }
+                let part = String::from_utf8(current).map_err(|_| {
+                    TransferError::Protocol(ERROR_ILLEGAL_OPERATION, "Malformed request".to_string())
+                })?;
+                parts.push(part);
+                current = Vec::new();
             } else {
                 current.push(byte);
             }
         }
 
-        if parts.len() < 2 {
-            return Err("Malformed request".into());
+        if parts.len() < 2 || parts[0].is_empty() || parts[1].is_empty() {
+            return Err(TransferError::Protocol(
+                ERROR_ILLEGAL_OPERATION,
+                "Malformed request".to_string(),
+            ));
         }
 
-        Ok((parts[0].clone(), parts[1].clone()))
+        let mut options = Vec::new();
+        for pair in parts[2..].chunks(2) {
+            // Some clients pad requests with trailing NULs
+            if pair.len() < 2 || pair[0].is_empty() {
+                break;
+            }
+            options.push((pair[0].to_ascii_lowercase(), pair[1].clone()));
+        }
+
+        Ok((parts[0].clone(), parts[1].to_ascii_lowercase(), options))
     }
 
     fn send_error(
         &self,
         addr: SocketAddr,
+        local: SocketAddr,
         error_code: u16,
         error_msg: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-
-        let mut packet = Vec::new();
-        packet.extend_from_slice(&ERROR.to_be_bytes());
-        packet.extend_from_slice(&error_code.to_be_bytes());
-        packet.extend_from_slice(error_msg.as_bytes());
-        packet.push(0);
-
-        socket.send_to(&packet, addr)?;
-        Ok(())
-    }
+    ) -> std::io::Result<()> {
+        if let Some(listener) = &self.listener {
+            send_from_listener(listener, &error_packet(error_code, error_msg), addr)?;
+            return Ok(());
+        }
 
-    fn get_local_ip(&self) -> String {
-        match UdpSocket::bind("0.0.0.0:0") {
-            Ok(socket) => {
-                if let Ok(_) = socket.connect("8.8.8.8:80") {
-                    if let Ok(addr) = socket.local_addr() {
-                        return addr.ip().to_string();
-                    }
-                }
+        let socket = match self.bind_reply_socket(local) {
+            // With the port range used up, an ephemeral port still gets the
+            // error to the client
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && self.port_range.is_some() => {
+                bind_udp(local, false, self.interface.as_deref())?
             }
-            Err(_) => {}
-        }
-        "127.0.0.1".to_string()
+            result => result?,
+        };
+        socket.send_to(&error_packet(error_code, error_msg), addr)?;
+        Ok(())
     }
 
     fn check_port_available(&self, port: u16) -> bool {
-        UdpSocket::bind(format!("0.0.0.0:{}", port)).is_ok()
+        self.bind_listener(port).is_ok()
     }
 
     fn suggest_alternative_ports(&self) {
@@ -715,13 +2926,592 @@ impl TFTPServer {
 impl Clone for TFTPServer {
     fn clone(&self) -> Self {
         TFTPServer {
+            name: self.name.clone(),
             port: self.port,
             directory: self.directory.clone(),
+            ip_mode: self.ip_mode,
+            listen: self.listen.clone(),
+            interface: self.interface.clone(),
+            port_range: self.port_range,
+            next_port: Arc::clone(&self.next_port),
+            single_port: self.single_port,
+            listener: self.listener.clone(),
+            routes: Arc::clone(&self.routes),
+            access: self.access,
+            acl: self.acl.clone(),
+            map: self.map.clone(),
+            absolute_paths: self.absolute_paths,
+            client_roots: Arc::clone(&self.client_roots),
+            no_overwrite: self.no_overwrite,
+            max_upload_size: self.max_upload_size,
+            quota: self.quota,
+            client_quota: self.client_quota,
+            client_uploads: Arc::clone(&self.client_uploads),
+            directory_usage: Arc::clone(&self.directory_usage),
+            rollover: self.rollover,
+            max_blksize: self.max_blksize,
+            max_windowsize: self.max_windowsize,
+            multicast_base: self.multicast_base,
+            multicast_sessions: Arc::clone(&self.multicast_sessions),
             active_transfers: Arc::clone(&self.active_transfers),
         }
     }
 }
 
+// The wildcard address of the same family as `addr`, for sockets that talk
+// to that peer
+fn unspecified_for(addr: SocketAddr) -> SocketAddr {
+    let ip: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+// Creates and binds a UDP socket by hand, because IPV6_V6ONLY and
+// SO_BINDTODEVICE both have to be set before bind() and so are out of reach
+// of UdpSocket::bind. `v6only` is ignored for IPv4 addresses.
+fn bind_udp(addr: SocketAddr, v6only: bool, device: Option<&str>) -> std::io::Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+
+    unsafe {
+        let fd = libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Owning the fd from here on closes it on every error path
+        let socket = UdpSocket::from_raw_fd(fd);
+
+        if addr.is_ipv6() {
+            let optval = v6only as libc::c_int;
+            if libc::setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                &optval as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            ) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        if let Some(device) = device
+            && libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                device.as_ptr() as *const libc::c_void,
+                device.len() as libc::socklen_t,
+            ) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let result = match addr {
+            SocketAddr::V4(addr) => {
+                let mut sockaddr: libc::sockaddr_in = std::mem::zeroed();
+                sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+                sockaddr.sin_port = addr.port().to_be();
+                sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+            SocketAddr::V6(addr) => {
+                let mut sockaddr: libc::sockaddr_in6 = std::mem::zeroed();
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = addr.port().to_be();
+                sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+                sockaddr.sin6_scope_id = addr.scope_id();
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+// Sends from a listening socket, which on a dual-stack listener needs IPv4
+// peers written as ::ffff:a.b.c.d again
+fn send_from_listener(socket: &UdpSocket, packet: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+    let addr = match (socket.local_addr()?, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        _ => addr,
+    };
+    socket.send_to(packet, addr)
+}
+
+// Sends multicast from the interface holding `address` (IP_MULTICAST_IF)
+// rather than the one the default route points at
+fn set_multicast_interface(socket: &UdpSocket, address: Ipv4Addr) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let interface = libc::in_addr {
+        s_addr: u32::from(address).to_be(),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &interface as *const libc::in_addr as *const libc::c_void,
+            std::mem::size_of::<libc::in_addr>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Asks the kernel to report the destination address of each datagram
+// (IP_PKTINFO, IPV6_RECVPKTINFO), which recv_request picks up
+fn enable_pktinfo(socket: &UdpSocket) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    let enable = |level, name| {
+        let optval: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &optval as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    };
+
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => enable(libc::IPPROTO_IP, libc::IP_PKTINFO),
+        SocketAddr::V6(_) => {
+            enable(libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)?;
+            // IPv4 requests on a dual-stack socket; best effort, since an
+            // IPv6-only socket never sees them anyway
+            let _ = enable(libc::IPPROTO_IP, libc::IP_PKTINFO);
+            Ok(())
+        }
+    }
+}
+
+// recv_from() that also returns the local address the datagram was sent to,
+// as a port 0 address to bind a reply socket to. `None` when the kernel did
+// not say, or when the request went to a broadcast or multicast address that
+// a reply cannot come from.
+fn recv_request(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut peer: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 16];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut peer as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let size = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let peer = match peer.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { *(&peer as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            SocketAddr::new(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).into(), u16::from_be(sin.sin_port))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { *(&peer as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )
+            .into()
+        }
+        _ => return Err(std::io::ErrorKind::InvalidData.into()),
+    };
+
+    // IP_PKTINFO wins over IPV6_PKTINFO for IPv4 requests on a dual-stack
+    // socket: its ipi_spec_dst is the interface address even for broadcasts
+    let mut local_v4 = None;
+    let mut local_v6 = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+        if header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_PKTINFO {
+            let info = unsafe { std::ptr::read_unaligned(data as *const libc::in_pktinfo) };
+            let ip = Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr));
+            local_v4 = Some(SocketAddr::new(ip.into(), 0));
+        } else if header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_PKTINFO {
+            let info = unsafe { std::ptr::read_unaligned(data as *const libc::in6_pktinfo) };
+            let ip = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+            // The scope only matters (and is only used) for link-local addresses
+            local_v6 = Some(match ip.to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(ip.into(), 0),
+                None => SocketAddrV6::new(ip, 0, 0, info.ipi6_ifindex).into(),
+            });
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    let local = local_v4.or(local_v6).filter(|local| match local.ip() {
+        IpAddr::V4(ip) => !ip.is_broadcast() && !ip.is_multicast() && !ip.is_unspecified(),
+        IpAddr::V6(ip) => !ip.is_multicast() && !ip.is_unspecified(),
+    });
+    Ok((size as usize, peer, local))
+}
+
+fn oack_packet(options: &[(String, String)]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&OACK.to_be_bytes());
+    for (name, value) in options {
+        packet.extend_from_slice(name.as_bytes());
+        packet.push(0);
+        packet.extend_from_slice(value.as_bytes());
+        packet.push(0);
+    }
+    packet
+}
+
+fn error_packet(error_code: u16, error_msg: &str) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&ERROR.to_be_bytes());
+    packet.extend_from_slice(&error_code.to_be_bytes());
+    packet.extend_from_slice(error_msg.as_bytes());
+    packet.push(0);
+    packet
+}
+
+// Bytes available to unprivileged users on the filesystem holding the open `directory`
+fn available_space(directory: &File) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::fstatvfs(directory.as_raw_fd(), &mut stat) } != 0 {
+        return None;
+    }
+
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// Whether `filename` names something under `prefix`, comparing whole path
+// components. Names that climb out with `..` are never within a prefix.
+fn path_within(filename: &str, prefix: &Path) -> bool {
+    use std::path::Component;
+
+    let mut path = PathBuf::new();
+    for component in Path::new(filename).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::ParentDir => return false,
+            _ => {}
+        }
+    }
+    path.starts_with(prefix)
+}
+
+// Applies map file rules to a filename in order, returning the rule that
+// rejected it if one did
+fn map_filename<'a>(rules: &'a [MapRule], filename: &str, client: IpAddr) -> Result<String, &'a MapRule> {
+    let mut filename = filename.to_string();
+
+    for rule in rules {
+        if rule.client.is_some_and(|network| !network.contains(client)) || !rule.pattern.is_match(&filename) {
+            continue;
+        }
+        match rule.action {
+            MapAction::Reject => return Err(rule),
+            MapAction::Replace => {
+                filename = rule.pattern.replace_all(&filename, rule.replacement.as_str()).into_owned();
+            }
+            MapAction::Rewrite => {
+                return Ok(rule.pattern.replace(&filename, rule.replacement.as_str()).into_owned());
+            }
+        }
+    }
+    Ok(filename)
+}
+
+// Reads a rules file such as a map file or ACL, one rule per line, with
+// `parse` given each line and where it came from. Blank lines and lines
+// starting with # are skipped.
+fn load_rules<T>(path: &Path, parse: impl Fn(&str, String) -> Result<T, String>) -> Result<Vec<T>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut rules = Vec::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let source = format!("{}:{}: {}", path.display(), number + 1, line);
+        let rule = parse(line, source).map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+// Parses a MAC address written as aa:bb:cc:dd:ee:ff or aa-bb-cc-dd-ee-ff
+fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let mut address = [0u8; 6];
+    let mut parts = value.split([':', '-']);
+
+    for byte in address.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(address)
+}
+
+// A MAC address as used in directory names: aa-bb-cc-dd-ee-ff
+fn format_mac(address: [u8; 6]) -> String {
+    address.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join("-")
+}
+
+// The MAC address of an IPv4 neighbour, from the kernel's ARP table. A client
+// that just sent us a request is normally in it.
+fn arp_lookup(client: IpAddr) -> Option<[u8; 6]> {
+    // Entries whose lookup has not completed have no usable address
+    const ATF_COM: u32 = 0x02;
+
+    let IpAddr::V4(client) = client else {
+        return None;
+    };
+    let table = std::fs::read_to_string("/proc/net/arp").ok()?;
+
+    // IP address, HW type, Flags, HW address, Mask, Device
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u32::from_str_radix(fields.get(2)?.trim_start_matches("0x"), 16).ok()?;
+        if fields.first()?.parse::<Ipv4Addr>().ok()? != client || flags & ATF_COM == 0 {
+            return None;
+        }
+        parse_mac(fields.get(3)?)
+    })
+}
+
+// Reduces a requested filename to plain components relative to the served
+// directory. `.` is dropped and `..` cancels the component before it. Names
+// that climb above the top, and absolute names unless they are stripped,
+// give `None`.
+fn normalize_path(filename: &str, absolute: AbsolutePaths) -> Option<String> {
+    let mut parts: Vec<&str> = Vec::new();
+
+    if filename.starts_with('/') && absolute == AbsolutePaths::Reject {
+        return None;
+    }
+    for part in filename.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            _ => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+// Makes a libc return value into an io::Result
+fn check_os(result: libc::c_int) -> std::io::Result<()> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// openat(2) relative to an open directory. Files it creates get the usual
+// 0666 less the umask.
+fn open_at(directory: &File, name: &CStr, flags: libc::c_int) -> std::io::Result<File> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let fd = unsafe { libc::openat(directory.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC, 0o666) };
+    check_os(fd)?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// Opens `path` relative to `root` such that neither it nor any symlink met
+// on the way can resolve to something outside `root`. Escapes fail with
+// EXDEV. Uses openat2(RESOLVE_BENEATH) and falls back to resolving the path
+// ourselves on kernels without it (before 5.6) or where seccomp blocks it.
+fn open_beneath(root: &Path, path: &Path, flags: libc::c_int) -> std::io::Result<File> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let root = File::open(root)?;
+    let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            c_path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd >= 0 {
+        return Ok(unsafe { File::from_raw_fd(fd as libc::c_int) });
+    }
+
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENOSYS) | Some(libc::EPERM) => walk_beneath(root, path.as_os_str().as_bytes(), flags),
+        _ => Err(e),
+    }
+}
+
+// The fallback for open_beneath: walks the path a component at a time with
+// O_NOFOLLOW, expanding symlinks by hand and keeping the directories passed
+// through so `..` can never rise above `root`.
+fn walk_beneath(root: File, path: &[u8], flags: libc::c_int) -> std::io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    // The same limit the kernel applies
+    const MAX_SYMLINKS: usize = 40;
+
+    let escape = || std::io::Error::from_raw_os_error(libc::EXDEV);
+    let components = |path: &[u8]| -> Vec<Vec<u8>> {
+        path.split(|&byte| byte == b'/')
+            .filter(|part| !part.is_empty())
+            .map(|part| part.to_vec())
+            .collect()
+    };
+
+    if path.starts_with(b"/") {
+        return Err(escape());
+    }
+    let mut remaining: VecDeque<Vec<u8>> = components(path).into();
+    let mut parents = Vec::new();
+    let mut current = root;
+    let mut symlinks = 0;
+    let mut target = vec![0u8; libc::PATH_MAX as usize];
+
+    while let Some(part) = remaining.pop_front() {
+        match part.as_slice() {
+            b"." => continue,
+            b".." => {
+                current = parents.pop().ok_or_else(escape)?;
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = CString::new(part)?;
+        let len = unsafe {
+            libc::readlinkat(
+                current.as_raw_fd(),
+                name.as_ptr(),
+                target.as_mut_ptr() as *mut libc::c_char,
+                target.len(),
+            )
+        };
+        if len >= 0 {
+            // A symlink: its target takes the place of this component
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(std::io::Error::from_raw_os_error(libc::ELOOP));
+            }
+            let link = &target[..len as usize];
+            if link.starts_with(b"/") {
+                return Err(escape());
+            }
+            for part in components(link).into_iter().rev() {
+                remaining.push_front(part);
+            }
+            continue;
+        }
+
+        if remaining.is_empty() {
+            return open_at(&current, &name, flags | libc::O_NOFOLLOW);
+        }
+        let next = open_at(&current, &name, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW)?;
+        parents.push(std::mem::replace(&mut current, next));
+    }
+
+    // The path ended on a directory, such as `.` or `a/..`
+    open_at(&current, c".", flags)
+}
+
+// Total size of the regular files under `path`. Symlinks are not followed,
+// and anything that cannot be read counts as empty.
+fn directory_usage(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_usage(&entry.path()),
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+// Parses a byte count with an optional K, M or G suffix (powers of 1024)
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1024),
+        'M' => (&value[..value.len() - 1], 1024 * 1024),
+        'G' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// Parses FIRST:LAST into a non-empty range of non-zero ports
+fn parse_port_range(value: &str) -> Option<(u16, u16)> {
+    let (first, last) = value.split_once(':')?;
+    let first = first.trim().parse::<u16>().ok()?;
+    let last = last.trim().parse::<u16>().ok()?;
+    (first > 0 && first <= last).then_some((first, last))
+}
+
 fn format_size(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
     let mut size = bytes as f64;
@@ -748,43 +3538,435 @@ fn format_size_compact(bytes: u64) -> String {
     format!("{:.1}{}", size, UNITS[unit_index])
 }
 
+fn print_usage() {
+    println!("Usage: tftpd-linux [OPTIONS] [PORT]");
+    println!();
+    println!("Serves files from the current directory over TFTP (default port 6969).");
+    println!();
+    println!("Options:");
+    println!("  --server NAME           Start another virtual server; the options after it apply to it alone");
+    println!("  --root DIR              Serve files from DIR instead of the current directory");
+    println!("  --read-only             Refuse all uploads");
+    println!("  --write-only            Refuse all downloads");
+    println!("  --acl FILE              Allow or deny clients by address with the rules in FILE");
+    println!("  --map-file FILE         Rewrite or reject requested filenames with the rules in FILE");
+    println!("  --map-test NAME         Print how the map file maps NAME, then exit");
+    println!("  --map-client ADDR       Client address to assume for --map-test");
+    println!("  --client-root MATCH=DIR Serve clients matching MATCH (a CIDR or MAC address) from DIR; may repeat");
+    println!("  --absolute-paths MODE   reject (default) or strip requests for absolute paths");
+    println!("  --no-overwrite          Refuse uploads that would replace an existing file");
+    println!("  --max-upload-size SIZE  Refuse uploads larger than SIZE (K, M, G suffixes)");
+    println!("  --quota SIZE            Refuse uploads once the directory holds SIZE bytes");
+    println!("  --client-quota SIZE     Limit how much each client may upload");
+    println!("  --max-blksize SIZE      Largest blksize granted to clients (default {})", MAX_BLKSIZE);
+    println!("  --max-windowsize N      Largest windowsize granted to clients (default {})", MAX_WINDOWSIZE);
+    println!("  --rollover 0|1|none     Block number after 65535 unless the client asks (default 0)");
+    println!("  --multicast GROUP:PORT  Offer RFC 2090 multicast, numbering groups up from GROUP");
+    println!("  --listen ADDR:PORT      Listen on this address only (repeatable)");
+    println!("  --interface NAME        Only serve clients on this network interface");
+    println!("  --port-range FIRST:LAST Use only these local ports for transfers");
+    println!("  --single-port           Answer from the listening port (for NAT, not RFC 1350)");
+    println!("  -4, --ipv4-only         Listen on IPv4 only");
+    println!("  -6, --ipv6-only         Listen on IPv6 only (IPV6_V6ONLY)");
+    println!("  -h, --help              Show this help");
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut port = 6969u16;
-
-    if args.len() > 1 {
-        match args[1].parse::<u16>() {
-            Ok(p) => port = p,
-            Err(_) => {
-                eprintln!("[ERROR] Invalid port number");
+    let mut args = env::args().skip(1);
+    // Options before the first --server are defaults for every server
+    let mut defaults = TFTPServer::new(6969, None);
+    let mut servers: Vec<TFTPServer> = Vec::new();
+    // Which list and access options the current server has set itself,
+    // rather than inherited from the defaults
+    let mut access_given = false;
+    let mut listen_given = false;
+    let mut roots_given = false;
+    let mut map_test = None;
+    let mut map_client = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+    while let Some(arg) = args.next() {
+        if arg == "--server" {
+            let Some(name) = args.next().filter(|name| !name.is_empty() && !name.starts_with('-')) else {
+                eprintln!("[ERROR] --server needs a name");
+                std::process::exit(1);
+            };
+            let mut server = defaults.clone();
+            server.name = Some(name);
+            // Each server keeps its own sessions and upload ledgers. Only
+            // the multicast sessions stay shared, so that no two of them
+            // are handed the same group.
+            server.active_transfers = Arc::new(Mutex::new(HashMap::new()));
+            server.routes = Arc::new(Mutex::new(HashMap::new()));
+            server.client_uploads = Arc::new(Mutex::new(HashMap::new()));
+            server.directory_usage = Arc::new(Mutex::new(HashMap::new()));
+            server.next_port = Arc::new(AtomicU32::new(0));
+            servers.push(server);
+            (access_given, listen_given, roots_given) = (false, false, false);
+            continue;
+        }
+
+        let server = servers.last_mut().unwrap_or(&mut defaults);
+        match arg.as_str() {
+            "--read-only" | "--write-only" => {
+                let mode = if arg == "--read-only" { Access::ReadOnly } else { Access::WriteOnly };
+                if access_given && server.access != mode {
+                    eprintln!("[ERROR] --read-only and --write-only cannot be combined");
+                    std::process::exit(1);
+                }
+                server.access = mode;
+                access_given = true;
+            }
+            "--no-overwrite" => server.no_overwrite = true,
+            "--map-file" => {
+                let Some(path) = args.next() else {
+                    eprintln!("[ERROR] --map-file needs a rules file");
+                    std::process::exit(1);
+                };
+                match load_rules(Path::new(&path), MapRule::parse) {
+                    Ok(rules) => server.map = Some(Arc::new(rules)),
+                    Err(e) => {
+                        eprintln!("[ERROR] Invalid map file: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--map-test" => match args.next() {
+                Some(name) => map_test = Some((name, servers.len())),
+                None => {
+                    eprintln!("[ERROR] --map-test needs a filename");
+                    std::process::exit(1);
+                }
+            },
+            "--map-client" => match args.next().and_then(|v| v.parse::<IpAddr>().ok()) {
+                Some(ip) => map_client = ip,
+                None => {
+                    eprintln!("[ERROR] --map-client needs an IP address");
+                    std::process::exit(1);
+                }
+            },
+            "--acl" => {
+                let Some(path) = args.next() else {
+                    eprintln!("[ERROR] --acl needs a rules file");
+                    std::process::exit(1);
+                };
+                match load_rules(Path::new(&path), AclRule::parse) {
+                    Ok(rules) => server.acl = Some(Arc::new(rules)),
+                    Err(e) => {
+                        eprintln!("[ERROR] Invalid ACL: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--max-upload-size" => match args.next().as_deref().and_then(parse_size) {
+                Some(size) => server.max_upload_size = Some(size),
+                None => {
+                    eprintln!("[ERROR] --max-upload-size needs a size such as 200M");
+                    std::process::exit(1);
+                }
+            },
+            "--quota" | "--client-quota" => match args.next().as_deref().and_then(parse_size) {
+                Some(size) if arg == "--quota" => server.quota = Some(size),
+                Some(size) => server.client_quota = Some(size),
+                None => {
+                    eprintln!("[ERROR] {} needs a size such as 10G", arg);
+                    std::process::exit(1);
+                }
+            },
+            "--client-root" => match args.next().as_deref().and_then(ClientRoot::parse) {
+                Some(mut root) => {
+                    // Fixed directories must exist. {mac} ones are checked per client.
+                    if !root.directory.contains("{mac}") {
+                        match std::fs::canonicalize(&root.directory) {
+                            Ok(directory) if directory.is_dir() => root.directory = directory.display().to_string(),
+                            _ => {
+                                eprintln!("[ERROR] --client-root: {} is not a directory", root.directory);
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    if !roots_given {
+                        server.client_roots = Arc::new(Vec::new());
+                        roots_given = true;
+                    }
+                    Arc::make_mut(&mut server.client_roots).push(root);
+                }
+                None => {
+                    eprintln!("[ERROR] --client-root needs a CIDR or MAC address and a directory, such as 10.1.0.0/16=/srv/tftp/line-a");
+                    std::process::exit(1);
+                }
+            },
+            "--root" => match args.next().map(std::fs::canonicalize) {
+                Some(Ok(directory)) if directory.is_dir() => server.directory = directory,
+                _ => {
+                    eprintln!("[ERROR] --root needs an existing directory");
+                    std::process::exit(1);
+                }
+            },
+            "--max-blksize" => match args.next().and_then(|v| v.parse::<usize>().ok()) {
+                Some(size) if (MIN_BLKSIZE..=MAX_BLKSIZE).contains(&size) => server.max_blksize = size,
+                _ => {
+                    eprintln!("[ERROR] --max-blksize needs a size from {} to {}", MIN_BLKSIZE, MAX_BLKSIZE);
+                    std::process::exit(1);
+                }
+            },
+            "--max-windowsize" => match args.next().and_then(|v| v.parse::<usize>().ok()) {
+                Some(size) if (1..=MAX_WINDOWSIZE).contains(&size) => server.max_windowsize = size,
+                _ => {
+                    eprintln!("[ERROR] --max-windowsize needs a number of blocks from 1 to {}", MAX_WINDOWSIZE);
+                    std::process::exit(1);
+                }
+            },
+            "--absolute-paths" => match args.next().as_deref() {
+                Some("reject") => server.absolute_paths = AbsolutePaths::Reject,
+                Some("strip") => server.absolute_paths = AbsolutePaths::Strip,
+                _ => {
+                    eprintln!("[ERROR] --absolute-paths needs reject or strip");
+                    std::process::exit(1);
+                }
+            },
+            "--rollover" => match args.next().as_deref().and_then(Rollover::parse) {
+                Some(policy) => server.rollover = policy,
+                None => {
+                    eprintln!("[ERROR] --rollover needs 0, 1 or none");
+                    std::process::exit(1);
+                }
+            },
+            "--multicast" => match args.next().and_then(|v| v.parse::<SocketAddrV4>().ok()) {
+                Some(group) if group.ip().is_multicast() => server.multicast_base = Some(group),
+                _ => {
+                    eprintln!("[ERROR] --multicast needs a multicast group and port, such as 239.255.69.1:1758");
+                    std::process::exit(1);
+                }
+            },
+            "--listen" => match args.next().and_then(|v| v.parse::<SocketAddr>().ok()) {
+                Some(addr) => {
+                    if !listen_given {
+                        server.listen.clear();
+                        listen_given = true;
+                    }
+                    server.listen.push(addr);
+                }
+                None => {
+                    eprintln!("[ERROR] --listen needs an address and port, such as 192.168.1.10:69 or [::1]:69");
+                    std::process::exit(1);
+                }
+            },
+            "--interface" => match args.next() {
+                Some(name) if !name.is_empty() => server.interface = Some(name),
+                _ => {
+                    eprintln!("[ERROR] --interface needs a network interface name, such as eth1");
+                    std::process::exit(1);
+                }
+            },
+            "--port-range" => match args.next().as_deref().and_then(parse_port_range) {
+                Some(range) => server.port_range = Some(range),
+                None => {
+                    eprintln!("[ERROR] --port-range needs FIRST:LAST ports, such as 50000:50100");
+                    std::process::exit(1);
+                }
+            },
+            "--single-port" => server.single_port = true,
+            "-4" | "--ipv4-only" => server.ip_mode = IpMode::Ipv4Only,
+            "-6" | "--ipv6-only" => server.ip_mode = IpMode::Ipv6Only,
+            "-h" | "--help" => {
+                print_usage();
+                return;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("[ERROR] Unknown option: {} (see --help)", arg);
                 std::process::exit(1);
             }
+            _ => match arg.parse::<u16>() {
+                Ok(port) => server.port = port,
+                Err(_) => {
+                    eprintln!("[ERROR] Invalid port number");
+                    std::process::exit(1);
+                }
+            },
         }
     }
 
-    if port < 1024 && unsafe { libc::geteuid() } != 0 {
-        println!(
-            "[INFO] Port {} requires root privileges. Using port 6969 instead.",
-            port
-        );
-        println!("[INFO] Run with sudo to use port 69, or specify a port > 1024");
-        port = 6969;
+    // Dry run: show what the map file does to a name without serving anything
+    if let Some((name, scope)) = map_test {
+        let scope = scope.checked_sub(1).map_or(&defaults, |index| &servers[index]);
+        let Some(rules) = &scope.map else {
+            eprintln!("[ERROR] --map-test needs --map-file");
+            std::process::exit(1);
+        };
+        match map_filename(rules, &name, map_client) {
+            Ok(mapped) => println!("{} -> {}", name, mapped),
+            Err(rule) => println!("{} -> rejected ({})", name, rule.source),
+        }
+        return;
     }
 
-    let server = TFTPServer::new(port, None);
+    if servers.is_empty() {
+        servers.push(defaults);
+    }
+    for server in &mut servers {
+        if server.port < 1024 && unsafe { libc::geteuid() } != 0 {
+            println!(
+                "[INFO] Port {} requires root privileges. Using port 6969 instead.",
+                server.port
+            );
+            println!("[INFO] Run with sudo to use port 69, or specify a port > 1024");
+            server.port = 6969;
+        }
+    }
 
-    match server.start() {
+    match TFTPServer::start(&servers) {
         Ok(_) => {}
         Err(e) => {
-            if let Some(io_error) = e.downcast_ref::<std::io::Error>() {
-                if io_error.kind() == std::io::ErrorKind::AddrInUse {
-                    println!("[ERROR] Port {} is already in use!", port);
-                    server.suggest_alternative_ports();
-                    std::process::exit(1);
-                }
+            // Alternative ports only make sense for a lone default listener
+            if let Some(io_error) = e.downcast_ref::<std::io::Error>()
+                && io_error.kind() == std::io::ErrorKind::AddrInUse
+                && servers.len() == 1
+                && servers[0].listen.is_empty()
+            {
+                println!("[ERROR] Port {} is already in use!", servers[0].port);
+                servers[0].suggest_alternative_ports();
+                std::process::exit(1);
             }
             eprintln!("[ERROR] Error starting server: {}", e);
             std::process::exit(1);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollover_numbers_blocks_past_65535() {
+        for rollover in [Rollover::Zero, Rollover::One, Rollover::Refuse] {
+            assert_eq!(rollover.wire_block(1), Some(1));
+            assert_eq!(rollover.wire_block(65535), Some(65535));
+        }
+
+        assert_eq!(Rollover::Zero.wire_block(65536), Some(0));
+        assert_eq!(Rollover::Zero.wire_block(65537), Some(1));
+        assert_eq!(Rollover::Zero.wire_block(2 * 65536), Some(0));
+
+        // Wrapping to 1 skips 0 every time round
+        assert_eq!(Rollover::One.wire_block(65536), Some(1));
+        assert_eq!(Rollover::One.wire_block(65537), Some(2));
+        assert_eq!(Rollover::One.wire_block(65535 + 65535), Some(65535));
+        assert_eq!(Rollover::One.wire_block(65535 + 65536), Some(1));
+
+        assert_eq!(Rollover::Refuse.wire_block(65536), None);
+        assert_eq!(Rollover::Refuse.wire_block(u64::MAX), None);
+    }
+
+    /// Decodes `encoded` delivered in blocks of `size` bytes
+    fn decode_in_blocks(encoded: &[u8], size: usize) -> Vec<u8> {
+        let mut decoder = NetasciiDecoder::default();
+        let mut decoded: Vec<u8> = encoded.chunks(size).flat_map(|block| decoder.decode(block)).collect();
+        decoded.extend_from_slice(decoder.finish());
+        decoded
+    }
+
+    #[test]
+    fn netascii_cr_lf_split_across_blocks() {
+        let mut decoder = NetasciiDecoder::default();
+        assert_eq!(decoder.decode(b"one\r"), b"one");
+        assert_eq!(decoder.decode(b"\ntwo"), b"\ntwo");
+        assert_eq!(decoder.finish(), b"");
+    }
+
+    #[test]
+    fn netascii_cr_nul_split_across_blocks() {
+        let mut decoder = NetasciiDecoder::default();
+        assert_eq!(decoder.decode(b"a\r"), b"a");
+        assert_eq!(decoder.decode(b"\0b"), b"\rb");
+        assert_eq!(decoder.finish(), b"");
+    }
+
+    #[test]
+    fn netascii_trailing_cr_is_kept() {
+        let mut decoder = NetasciiDecoder::default();
+        assert_eq!(decoder.decode(b"end\r"), b"end");
+        assert_eq!(decoder.finish(), b"\r");
+
+        // A bare CR in the middle is not valid netascii, but is not lost
+        assert_eq!(decode_in_blocks(b"a\rb", 512), b"a\rb");
+    }
+
+    #[test]
+    fn netascii_round_trip() {
+        let original = b"line one\nline two\r\nbare cr\r\rend\n\r\0\r".to_vec();
+        let mut encoded = Vec::new();
+        NetasciiReader::new(&original[..]).read_to_end(&mut encoded).unwrap();
+
+        // Every block size puts the block boundaries somewhere else
+        for size in 1..=encoded.len() {
+            assert_eq!(decode_in_blocks(&encoded, size), original, "blocks of {}", size);
+        }
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let network = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(network.contains(ip("10.1.255.7")));
+        assert!(!network.contains(ip("10.2.0.1")));
+        assert!(!network.contains(ip("::ffff:10.1.0.1")), "families never match");
+
+        let everyone = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(everyone.contains(ip("192.0.2.1")));
+        assert!(everyone.contains(ip("255.255.255.255")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("2001:db8::1")));
+
+        let host = Cidr::parse("2001:db8::1/128").unwrap();
+        assert!(host.contains(ip("2001:db8::1")));
+        assert!(!host.contains(ip("2001:db8::2")));
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains(ip("192.0.2.1")));
+        assert!(!Cidr::parse("192.0.2.1").unwrap().contains(ip("192.0.2.2")));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("::/129").is_none());
+    }
+
+    fn map_rules(lines: &[&str]) -> Vec<MapRule> {
+        lines.iter().map(|line| MapRule::parse(line, line.to_string()).unwrap()).collect()
+    }
+
+    #[test]
+    fn map_rule_parse_errors() {
+        let error = |line: &str| MapRule::parse(line, String::new()).unwrap_err();
+        assert_eq!(error("move a b"), "expected replace, rewrite or reject");
+        assert_eq!(error("replace"), "expected a regular expression");
+        assert_eq!(error("rewrite ^a"), "expected a replacement");
+        assert_eq!(error("reject ^a 10.0.0.0/8 extra"), "too many fields");
+        assert_eq!(error("replace a b not-a-network"), "invalid client network 'not-a-network'");
+        assert!(MapRule::parse("replace ( b", String::new()).is_err());
+    }
+
+    #[test]
+    fn map_replace_carries_on_and_rewrite_stops() {
+        let rules = map_rules(&[r"replace \\ /", r"rewrite ^/ boot/", r"replace boot x"]);
+        let client = ip("192.0.2.1");
+        assert_eq!(map_filename(&rules, r"\pxe\menu.cfg", client).unwrap(), "boot/pxe/menu.cfg");
+        assert_eq!(map_filename(&rules, "boot.img", client).unwrap(), "x.img");
+        assert_eq!(map_filename(&rules, "plain", client).unwrap(), "plain");
+
+        let rules = map_rules(&[r"rewrite ^(\w+)\.cfg$ cfg/$1.conf"]);
+        assert_eq!(map_filename(&rules, "pxe.cfg", client).unwrap(), "cfg/pxe.conf");
+    }
+
+    #[test]
+    fn map_reject_and_client_scoping() {
+        let rules = map_rules(&["reject \\.\\./ 10.0.0.0/8", "rewrite ^ lab/ 10.0.0.0/8", "reject ^secret"]);
+        let lab = ip("10.9.0.1");
+        let other = ip("192.0.2.1");
+
+        assert_eq!(map_filename(&rules, "a/../b", lab).unwrap_err().source, "reject \\.\\./ 10.0.0.0/8");
+        assert_eq!(map_filename(&rules, "a/../b", other).unwrap(), "a/../b");
+        assert_eq!(map_filename(&rules, "image", lab).unwrap(), "lab/image");
+        assert_eq!(map_filename(&rules, "image", other).unwrap(), "image");
+        assert!(map_filename(&rules, "secret.txt", other).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// Harness shared by the integration tests: runs the server binary over a
+// scratch directory and builds the packets clients send.
+
+// Each test crate uses only part of this module
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const RRQ: u16 = 1;
+pub const WRQ: u16 = 2;
+pub const DATA: u16 = 3;
+pub const ACK: u16 = 4;
+pub const ERROR: u16 = 5;
+pub const OACK: u16 = 6;
+
+pub const ERROR_FILE_NOT_FOUND: u16 = 1;
+pub const ERROR_ACCESS_VIOLATION: u16 = 2;
+
+/// A server process serving a scratch directory, stopped on drop
+pub struct Server {
+    child: Child,
+    pub directory: PathBuf,
+    pub addr: SocketAddr,
+}
+
+impl Server {
+    /// Creates a scratch directory, lets `setup` fill it, then starts the
+    /// server there over IPv4 with `args` in front of the port
+    pub fn start(name: &str, args: &[&str], setup: impl FnOnce(&Path)) -> Self {
+        let directory = std::env::temp_dir().join(format!("tftpd-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        setup(&directory);
+
+        // Borrow a free port from the kernel for the server to listen on
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_tftpd-linux"))
+            .args(args)
+            .args(["--ipv4-only", &port.to_string()])
+            .current_dir(&directory)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Server {
+            child,
+            directory,
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// An RRQ or WRQ in octet mode, with options as name and value pairs
+pub fn request(opcode: u16, filename: &str, options: &[(&str, &str)]) -> Vec<u8> {
+    let mut packet = opcode.to_be_bytes().to_vec();
+    packet.extend_from_slice(filename.as_bytes());
+    packet.push(0);
+    packet.extend_from_slice(b"octet\0");
+    for (name, value) in options {
+        packet.extend_from_slice(name.as_bytes());
+        packet.push(0);
+        packet.extend_from_slice(value.as_bytes());
+        packet.push(0);
+    }
+    packet
+}
+
+pub fn packet(opcode: u16, block: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = opcode.to_be_bytes().to_vec();
+    packet.extend_from_slice(&block.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub fn client() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").unwrap()
+}
+
+/// Splits a packet into its opcode, block number or error code, and payload
+pub fn split(packet: &[u8]) -> (u16, u16, Vec<u8>) {
+    let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+    let block = u16::from_be_bytes([packet[2], packet[3]]);
+    (opcode, block, packet[4..].to_vec())
+}
+
+/// Sends a request until the server, which may still be starting, answers.
+/// Returns the reply and the address it came from, and leaves the socket
+/// waiting up to five seconds for whatever follows.
+pub fn first_reply(socket: &UdpSocket, server: &Server, request: &[u8]) -> (Vec<u8>, SocketAddr) {
+    socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut buffer = vec![0; 65536];
+    loop {
+        socket.send_to(request, server.addr).unwrap();
+        match socket.recv_from(&mut buffer) {
+            Ok((size, from)) => {
+                socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                return (buffer[..size].to_vec(), from);
+            }
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            Err(e) => panic!("server never answered: {}", e),
+        }
+    }
+}
+
+/// Waits for a completed upload to be moved into place, which may happen
+/// just after its final block is acknowledged
+pub fn wait_for(path: &Path, contents: &[u8]) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while fs::read(path).ok().as_deref() != Some(contents) {
+        assert!(Instant::now() < deadline, "{} never appeared", path.display());
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,154 @@
+// Throws filenames built to escape the served directory at the server, by
+// path, by absolute name and through symlinks, and checks that reads and
+// writes stay inside while ordinary names still work.
+
+mod common;
+
+use common::{
+    ACK, DATA, ERROR, ERROR_ACCESS_VIOLATION, ERROR_FILE_NOT_FOUND, RRQ, Server, WRQ, client, first_reply, packet, request,
+    split, wait_for,
+};
+use std::fs;
+use std::os::unix::fs::symlink;
+
+/// Starts a server on `root` inside the scratch directory, with a secret
+/// file next to it that no request may reach
+fn start(name: &str, args: &[&str]) -> Server {
+    let args: Vec<&str> = ["--root", "root"].iter().chain(args).copied().collect();
+    Server::start(name, &args, |base| {
+        let root = base.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+
+        fs::write(base.join("secret.txt"), b"secret").unwrap();
+        fs::write(root.join("hello.txt"), b"hello").unwrap();
+        fs::write(root.join("sub/inner.txt"), b"inner").unwrap();
+        symlink("sub/inner.txt", root.join("inner-link")).unwrap();
+        symlink("../secret.txt", root.join("escape-link")).unwrap();
+        symlink(base.join("secret.txt"), root.join("absolute-link")).unwrap();
+        symlink("..", root.join("up")).unwrap();
+        symlink("../../secret.txt", root.join("sub/deep-escape")).unwrap();
+    })
+}
+
+/// Reads a file small enough for one block, or returns the error code
+fn read(server: &Server, filename: &str) -> Result<Vec<u8>, u16> {
+    let socket = client();
+    let (reply, from) = first_reply(&socket, server, &request(RRQ, filename, &[]));
+    let (opcode, block, payload) = split(&reply);
+    match opcode {
+        DATA => {
+            assert!(payload.len() < 512, "test files fit in one block");
+            socket.send_to(&packet(ACK, block, &[]), from).unwrap();
+            Ok(payload)
+        }
+        ERROR => Err(block),
+        _ => panic!("unexpected opcode {} for {}", opcode, filename),
+    }
+}
+
+/// Writes a one-block file, or returns the error code
+fn write(server: &Server, filename: &str, contents: &[u8]) -> Result<(), u16> {
+    let socket = client();
+    let (reply, from) = first_reply(&socket, server, &request(WRQ, filename, &[]));
+    let (opcode, block, _) = split(&reply);
+    match opcode {
+        ACK => {
+            socket.send_to(&packet(DATA, 1, contents), from).unwrap();
+
+            let mut buffer = [0; 1024];
+            let (size, _) = socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..size], &packet(ACK, 1, &[])[..]);
+            Ok(())
+        }
+        ERROR => Err(block),
+        _ => panic!("unexpected opcode {} for {}", opcode, filename),
+    }
+}
+
+#[test]
+fn parent_directory_names_are_refused() {
+    let server = start("hostile-parent", &[]);
+
+    for name in [
+        "../secret.txt",
+        "sub/../../secret.txt",
+        "./../secret.txt",
+        "sub/./../../secret.txt",
+        "..",
+        "../root/hello.txt",
+        "../../../../../../etc/passwd",
+    ] {
+        assert_eq!(read(&server, name), Err(ERROR_ACCESS_VIOLATION), "read of {}", name);
+    }
+}
+
+#[test]
+fn names_that_stay_inside_are_normalized() {
+    let server = start("hostile-normalize", &[]);
+
+    for name in [
+        "hello.txt",
+        "./hello.txt",
+        "sub/../hello.txt",
+        "sub//./../hello.txt",
+        "sub/inner.txt/../../hello.txt",
+    ] {
+        assert_eq!(read(&server, name).as_deref(), Ok(&b"hello"[..]), "read of {}", name);
+    }
+    assert_eq!(read(&server, "sub/inner.txt").as_deref(), Ok(&b"inner"[..]));
+
+    // Dots that are not whole components are ordinary names
+    for name in ["...", "..../hello.txt", "..hello.txt", "hello.txt.."] {
+        assert_eq!(read(&server, name), Err(ERROR_FILE_NOT_FOUND), "read of {:?}", name);
+    }
+}
+
+#[test]
+fn absolute_names_are_refused_by_default() {
+    let server = start("hostile-absolute", &[]);
+
+    let inside = server.directory.join("root").join("hello.txt");
+    for name in ["/etc/passwd", "/hello.txt", inside.to_str().unwrap(), "//hello.txt"] {
+        assert_eq!(read(&server, name), Err(ERROR_ACCESS_VIOLATION), "read of {}", name);
+    }
+}
+
+#[test]
+fn absolute_names_can_be_stripped() {
+    let server = start("hostile-strip", &["--absolute-paths", "strip"]);
+
+    assert_eq!(read(&server, "/hello.txt").as_deref(), Ok(&b"hello"[..]));
+    assert_eq!(read(&server, "//sub/inner.txt").as_deref(), Ok(&b"inner"[..]));
+    assert_eq!(read(&server, "/etc/passwd"), Err(ERROR_FILE_NOT_FOUND));
+    assert_eq!(read(&server, "/../secret.txt"), Err(ERROR_ACCESS_VIOLATION));
+}
+
+#[test]
+fn symlinks_may_not_leave_the_tree() {
+    let server = start("hostile-symlink", &[]);
+
+    assert_eq!(read(&server, "inner-link").as_deref(), Ok(&b"inner"[..]));
+    assert_eq!(read(&server, "up/root/hello.txt"), Err(ERROR_ACCESS_VIOLATION));
+    for name in ["escape-link", "absolute-link", "up/secret.txt", "sub/deep-escape"] {
+        assert_eq!(read(&server, name), Err(ERROR_ACCESS_VIOLATION), "read of {}", name);
+    }
+}
+
+#[test]
+fn uploads_stay_inside() {
+    let server = start("hostile-upload", &[]);
+
+    for name in ["../evil.txt", "/tmp/evil.txt", "up/evil.txt", "sub/../../evil.txt"] {
+        assert_eq!(write(&server, name, b"evil"), Err(ERROR_ACCESS_VIOLATION), "write of {}", name);
+    }
+    assert!(!server.directory.join("evil.txt").exists());
+
+    // Uploading over a symlink replaces the link, not what it points to
+    assert_eq!(write(&server, "escape-link", b"replaced"), Ok(()));
+    wait_for(&server.directory.join("root").join("escape-link"), b"replaced");
+    assert!(!server.directory.join("root").join("escape-link").is_symlink());
+    assert_eq!(fs::read(server.directory.join("secret.txt")).unwrap(), b"secret");
+
+    assert_eq!(write(&server, "sub/../new.txt", b"new"), Ok(()));
+    wait_for(&server.directory.join("root").join("new.txt"), b"new");
+}
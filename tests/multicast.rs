@@ -0,0 +1,68 @@
+// Reads a file over RFC 2090 multicast on loopback: the group is joined on
+// 127.0.0.1, so DATA only arrives if the server sends it out of the
+// interface the request came in on.
+
+mod common;
+
+use common::{ACK, DATA, OACK, RRQ, Server, client, first_reply, packet, request};
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// Splits an OACK into option names and values
+fn options(payload: &[u8]) -> Vec<(String, String)> {
+    let fields: Vec<String> = payload
+        .split(|&byte| byte == 0)
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect();
+    fields.chunks_exact(2).map(|pair| (pair[0].to_lowercase(), pair[1].clone())).collect()
+}
+
+#[test]
+fn multicast_data_leaves_through_the_request_interface() {
+    let group_port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let group_arg = format!("239.255.69.1:{}", group_port);
+    let contents: Vec<u8> = (0..2000u32).map(|i| (i % 251) as u8).collect();
+    let server = Server::start("multicast", &["--multicast", &group_arg], |directory| {
+        fs::write(directory.join("image.bin"), &contents).unwrap();
+    });
+
+    // The OACK comes by unicast
+    let socket = client();
+    let (reply, from) = first_reply(&socket, &server, &request(RRQ, "image.bin", &[("multicast", "")]));
+    assert_eq!(u16::from_be_bytes([reply[0], reply[1]]), OACK);
+    let value = options(&reply[2..])
+        .into_iter()
+        .find(|(name, _)| name == "multicast")
+        .map(|(_, value)| value)
+        .expect("multicast was not acknowledged");
+    let fields: Vec<&str> = value.split(',').collect();
+    let group: Ipv4Addr = fields[0].parse().unwrap();
+    let port: u16 = fields[1].parse().unwrap();
+    assert_eq!(fields[2], "1", "the only client is the master");
+
+    let receiver = UdpSocket::bind(SocketAddr::from((group, port))).unwrap();
+    receiver.join_multicast_v4(&group, &Ipv4Addr::LOCALHOST).unwrap();
+    receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.send_to(&packet(ACK, 0, &[]), from).unwrap();
+
+    let mut buffer = [0; 1024];
+    let mut received = Vec::new();
+    let mut expected = 1;
+    loop {
+        let (size, source) = receiver.recv_from(&mut buffer).expect("no multicast DATA arrived");
+        assert_eq!(source.ip(), from.ip(), "DATA left through another interface");
+        assert_eq!(u16::from_be_bytes([buffer[0], buffer[1]]), DATA);
+        let block = u16::from_be_bytes([buffer[2], buffer[3]]);
+        if block != expected {
+            continue;
+        }
+        received.extend_from_slice(&buffer[4..size]);
+        socket.send_to(&packet(ACK, block, &[]), from).unwrap();
+        if size - 4 < 512 {
+            break;
+        }
+        expected += 1;
+    }
+    assert_eq!(received, contents);
+}
@@ -0,0 +1,98 @@
+// Moves files of more than 65535 blocks with an 8-byte blksize, so block
+// numbers wrap, with both rollover=0 and rollover=1. A stale ACK sent right
+// after the wrap must not make the server resend.
+
+mod common;
+
+use common::{ACK, DATA, OACK, RRQ, Server, WRQ, client, first_reply, packet, request, split, wait_for};
+use std::fs;
+use std::net::UdpSocket;
+
+const BLKSIZE: usize = 8;
+/// Blocks in the test file, the last one short
+const BLOCKS: u64 = 65540;
+
+fn contents() -> Vec<u8> {
+    (0..(BLOCKS as usize - 1) * BLKSIZE + 3).map(|i| (i % 251) as u8).collect()
+}
+
+/// The number block `position` (from 1) carries with rollover to `first`
+fn wire_block(position: u64, first: u64) -> u16 {
+    if position <= 65535 {
+        position as u16
+    } else {
+        (first + (position - 65536) % (65536 - first)) as u16
+    }
+}
+
+fn start(name: &str) -> Server {
+    Server::start(name, &[], |directory| {
+        fs::write(directory.join("big.bin"), contents()).unwrap();
+    })
+}
+
+fn recv(socket: &UdpSocket) -> (u16, u16, Vec<u8>) {
+    let mut buffer = [0; 64];
+    let (size, _) = socket.recv_from(&mut buffer).unwrap();
+    split(&buffer[..size])
+}
+
+fn download(server: &Server, rollover: &str) {
+    let first = rollover.parse().unwrap();
+    let options = [("blksize", "8"), ("rollover", rollover)];
+    let socket = client();
+    let (reply, from) = first_reply(&socket, server, &request(RRQ, "big.bin", &options));
+    assert_eq!(split(&reply).0, OACK);
+    socket.send_to(&packet(ACK, 0, &[]), from).unwrap();
+
+    let mut received = Vec::new();
+    for position in 1..=BLOCKS {
+        let (opcode, block, payload) = recv(&socket);
+        assert_eq!((opcode, block), (DATA, wire_block(position, first)), "block {}", position);
+        received.extend_from_slice(&payload);
+
+        // Repeat the ACK for the block before the wrap once it is over
+        if position == 65536 {
+            socket.send_to(&packet(ACK, 65535, &[]), from).unwrap();
+        }
+        socket.send_to(&packet(ACK, block, &[]), from).unwrap();
+    }
+    assert_eq!(received, contents());
+}
+
+fn upload(server: &Server, rollover: &str) {
+    let first = rollover.parse().unwrap();
+    let options = [("blksize", "8"), ("rollover", rollover)];
+    let socket = client();
+    let (reply, from) = first_reply(&socket, server, &request(WRQ, "upload.bin", &options));
+    assert_eq!(split(&reply).0, OACK);
+
+    let contents = contents();
+    for (index, chunk) in contents.chunks(BLKSIZE).enumerate() {
+        let block = wire_block(index as u64 + 1, first);
+        socket.send_to(&packet(DATA, block, chunk), from).unwrap();
+        let (opcode, acked, _) = recv(&socket);
+        assert_eq!((opcode, acked), (ACK, block), "block {}", index + 1);
+    }
+    wait_for(&server.directory.join("upload.bin"), &contents);
+}
+
+#[test]
+fn reads_wrap_to_zero() {
+    download(&start("rollover-read-0"), "0");
+}
+
+#[test]
+fn reads_wrap_to_one() {
+    download(&start("rollover-read-1"), "1");
+}
+
+#[test]
+fn writes_wrap_to_zero() {
+    upload(&start("rollover-write-0"), "0");
+}
+
+#[test]
+fn writes_wrap_to_one() {
+    upload(&start("rollover-write-1"), "1");
+}
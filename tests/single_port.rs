@@ -0,0 +1,114 @@
+// Runs the server in single-port mode and checks that a loopback client only
+// ever hears from the listening port, for reads, writes and errors alike.
+
+mod common;
+
+use common::{ACK, DATA, ERROR, RRQ, Server, WRQ, client, first_reply, packet, request, split, wait_for};
+use std::fs;
+use std::net::UdpSocket;
+
+fn start(name: &str) -> Server {
+    Server::start(name, &["--single-port"], |_| {})
+}
+
+/// Receives one packet, checking it came from the listening port
+fn recv(socket: &UdpSocket, server: &Server) -> (u16, u16, Vec<u8>) {
+    let mut buffer = [0; 1024];
+    let (size, from) = socket.recv_from(&mut buffer).unwrap();
+    assert_eq!(from, server.addr, "reply did not come from the listening port");
+    split(&buffer[..size])
+}
+
+/// The first answer to a request, checking it came from the listening port
+fn reply_to(socket: &UdpSocket, server: &Server, request: &[u8]) -> (u16, u16, Vec<u8>) {
+    let (reply, from) = first_reply(socket, server, request);
+    assert_eq!(from, server.addr, "reply did not come from the listening port");
+    split(&reply)
+}
+
+#[test]
+fn read_is_served_from_the_listening_port() {
+    let server = start("single-port-read");
+    let contents: Vec<u8> = (0..1300u32).map(|i| (i % 251) as u8).collect();
+    fs::write(server.directory.join("image.bin"), &contents).unwrap();
+
+    let socket = client();
+    let mut received = Vec::new();
+    let (mut opcode, mut block, mut payload) = reply_to(&socket, &server, &request(RRQ, "image.bin", &[]));
+    loop {
+        assert_eq!(opcode, DATA);
+        received.extend_from_slice(&payload);
+        socket.send_to(&packet(ACK, block, &[]), server.addr).unwrap();
+        if payload.len() < 512 {
+            break;
+        }
+        (opcode, block, payload) = recv(&socket, &server);
+    }
+
+    assert_eq!(block, 3);
+    assert_eq!(received, contents);
+}
+
+#[test]
+fn write_is_received_on_the_listening_port() {
+    let server = start("single-port-write");
+    let contents: Vec<u8> = (0..700u32).map(|i| (i % 13) as u8).collect();
+
+    let socket = client();
+    let (opcode, block, _) = reply_to(&socket, &server, &request(WRQ, "upload.bin", &[]));
+    assert_eq!((opcode, block), (ACK, 0));
+
+    for (i, chunk) in contents.chunks(512).enumerate() {
+        let block = i as u16 + 1;
+        socket.send_to(&packet(DATA, block, chunk), server.addr).unwrap();
+        let (opcode, acked, _) = recv(&socket, &server);
+        assert_eq!((opcode, acked), (ACK, block));
+    }
+
+    wait_for(&server.directory.join("upload.bin"), &contents);
+}
+
+#[test]
+fn concurrent_clients_are_kept_apart() {
+    let server = start("single-port-concurrent");
+    fs::write(server.directory.join("a.txt"), vec![b'a'; 1000]).unwrap();
+    fs::write(server.directory.join("b.txt"), vec![b'b'; 1000]).unwrap();
+
+    let first = client();
+    let second = client();
+    let (_, _, a1) = reply_to(&first, &server, &request(RRQ, "a.txt", &[]));
+    let (_, _, b1) = reply_to(&second, &server, &request(RRQ, "b.txt", &[]));
+    assert!(a1.iter().all(|&byte| byte == b'a'));
+    assert!(b1.iter().all(|&byte| byte == b'b'));
+
+    // Acknowledging out of step still gets each client its own next block
+    second.send_to(&packet(ACK, 1, &[]), server.addr).unwrap();
+    first.send_to(&packet(ACK, 1, &[]), server.addr).unwrap();
+    let (_, block, b2) = recv(&second, &server);
+    assert_eq!((block, b2.len()), (2, 488));
+    assert!(b2.iter().all(|&byte| byte == b'b'));
+    let (_, block, a2) = recv(&first, &server);
+    assert_eq!((block, a2.len()), (2, 488));
+    assert!(a2.iter().all(|&byte| byte == b'a'));
+}
+
+#[test]
+fn errors_come_from_the_listening_port() {
+    let server = start("single-port-error");
+
+    let socket = client();
+    let (opcode, code, _) = reply_to(&socket, &server, &request(RRQ, "missing.bin", &[]));
+    assert_eq!((opcode, code), (ERROR, 1));
+}
+
+#[test]
+fn packets_without_a_session_get_unknown_transfer_id() {
+    let server = start("single-port-unknown");
+
+    let socket = client();
+    let (opcode, code, _) = reply_to(&socket, &server, &packet(ACK, 1, &[]));
+    assert_eq!((opcode, code), (ERROR, 5));
+    socket.send_to(&packet(DATA, 1, b"stray"), server.addr).unwrap();
+    let (opcode, code, _) = recv(&socket, &server);
+    assert_eq!((opcode, code), (ERROR, 5));
+}